@@ -3,8 +3,18 @@
 
 #include <sys/types.h>
 
+#define DT_UNKNOWN 0
+#define DT_FIFO 1
+#define DT_CHR 2
+#define DT_DIR 4
+#define DT_BLK 6
+#define DT_REG 8
+
 typedef struct dirent {
 	ino_t d_ino;
+	off_t d_off;
+	unsigned short d_reclen;
+	unsigned char d_type;
 	char d_name[256];
 } dirent;
 
@@ -12,5 +22,7 @@ typedef struct __dirstream DIR;
 
 DIR* opendir(const char *);
 dirent* readdir(DIR *);
+int closedir(DIR *);
+ssize_t getdents64(int fd, void *dirp, size_t count);
 
 #endif //__DIRENT_H
@@ -0,0 +1,28 @@
+#ifndef __ERRNO_H
+#define __ERRNO_H
+
+#define EPERM 1
+#define ENOENT 2
+#define E2BIG 7
+#define ENOEXEC 8
+#define EBADF 9
+#define ECHILD 10
+#define ENOMEM 12
+#define EACCES 13
+#define EFAULT 14
+#define EEXIST 17
+#define EXDEV 18
+#define ENODEV 19
+#define ENOTDIR 20
+#define EISDIR 21
+#define EINVAL 22
+#define EMFILE 24
+#define EFBIG 27
+#define ESPIPE 29
+#define EPIPE 32
+#define ENOSYS 38
+#define ENOTEMPTY 39
+
+extern int errno;
+
+#endif //__ERRNO_H
@@ -1,6 +1,22 @@
 #ifndef __FCNTL_H
 #define __FCNTL_H
 
-int open(const char *path, int oflag);
+#define O_RDONLY 00
+#define O_WRONLY 01
+#define O_RDWR 02
+#define O_ACCMODE 03
+#define O_CREAT 0100
+#define O_EXCL 0200
+#define O_TRUNC 01000
+#define O_APPEND 02000
+#define O_CLOEXEC 02000000
+
+#define F_GETFD 1
+#define F_SETFD 2
+
+#define FD_CLOEXEC 1
+
+int open(const char *path, int oflag, ...);
+int fcntl(int fildes, int cmd, ...);
 
 #endif //__FCNTL_H
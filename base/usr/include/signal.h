@@ -0,0 +1,8 @@
+#ifndef __SIGNAL_H
+#define __SIGNAL_H
+
+#define SIGILL 4
+#define SIGKILL 9
+#define SIGSEGV 11
+
+#endif //__SIGNAL_H
@@ -2,6 +2,7 @@
 #define __STDINT_H
 
 typedef unsigned short uint16_t;
+typedef unsigned int uint32_t;
 typedef unsigned long uint64_t;
 typedef long int64_t;
 
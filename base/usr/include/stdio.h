@@ -0,0 +1,6 @@
+#ifndef __STDIO_H
+#define __STDIO_H
+
+int rename(const char *old, const char *new);
+
+#endif // __STDIO_H
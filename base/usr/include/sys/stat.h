@@ -2,15 +2,34 @@
 #define __STAT_H
 
 #define S_IFMT  00170000
+#define S_IFIFO 0010000
+#define S_IFCHR 0020000
 #define S_IFDIR 0040000
+#define S_IFREG 0100000
 
 #define S_ISDIR(m) (((m) & S_IFMT) == S_IFDIR)
+#define S_ISREG(m) (((m) & S_IFMT) == S_IFREG)
+#define S_ISFIFO(m) (((m) & S_IFMT) == S_IFIFO)
 
 struct stat {
+	dev_t st_dev;
+	ino_t st_ino;
 	mode_t st_mode;
+	nlink_t st_nlink;
+	uid_t st_uid;
+	gid_t st_gid;
+	dev_t st_rdev;
 	off_t st_size;
+	blksize_t st_blksize;
+	blkcnt_t st_blocks;
+	time_t st_atime;
+	time_t st_mtime;
+	time_t st_ctime;
 };
 
+int stat(const char *path, struct stat *buf);
+int lstat(const char *path, struct stat *buf);
 int fstat(int fildes, struct stat *buf);
+int mkdir(const char *path, mode_t mode);
 
 #endif //__STAT_H
@@ -6,6 +6,13 @@
 typedef int64_t off_t;
 typedef uint16_t mode_t;
 typedef uint64_t ino_t;
+typedef uint64_t dev_t;
+typedef uint64_t nlink_t;
+typedef uint32_t uid_t;
+typedef uint32_t gid_t;
+typedef int64_t blksize_t;
+typedef int64_t blkcnt_t;
+typedef int64_t time_t;
 
 typedef int64_t ssize_t;
 typedef uint64_t size_t;
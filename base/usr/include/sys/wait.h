@@ -0,0 +1,16 @@
+#ifndef __WAIT_H
+#define __WAIT_H
+
+#include <sys/types.h>
+
+#define WNOHANG 1
+
+#define WEXITSTATUS(s) (((s) & 0xFF00) >> 8)
+#define WIFEXITED(s) (((s) & 0x7F) == 0)
+#define WTERMSIG(s) ((s) & 0x7F)
+#define WIFSIGNALED(s) (WTERMSIG(s) != 0)
+
+pid_t wait(int *stat_loc);
+pid_t waitpid(pid_t pid, int *stat_loc, int options);
+
+#endif //__WAIT_H
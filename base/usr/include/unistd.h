@@ -7,11 +7,29 @@
 #define STDOUT_FILENO 1
 #define STDERR_FILENO 2
 
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+extern char **environ;
+
 int chdir(const char *path);
 char *getcwd(char *buf, size_t size);
 ssize_t read(int fildes, void *buf, size_t nbyte);
 ssize_t write(int fildes, const void * buf, size_t nbyte);
+ssize_t pread(int fildes, void *buf, size_t nbyte, off_t offset);
+ssize_t pwrite(int fildes, const void *buf, size_t nbyte, off_t offset);
+off_t lseek(int fildes, off_t offset, int whence);
+int close(int fildes);
+int dup(int fildes);
+int dup2(int fildes, int fildes2);
+int pipe(int fildes[2]);
 pid_t fork(void);
 int exec(char *pathname);
+int execve(const char *path, char *const argv[], char *const envp[]);
+int link(const char *path1, const char *path2);
+int unlink(const char *path);
+int rmdir(const char *path);
+void sync(void);
 
 #endif // __UNISTD_H
@@ -68,9 +68,11 @@ enable_paging:
 	or eax, 0x101
 	wrmsr
 
-	;; Enable paging.
+	;; Enable paging, and write protection for supervisor writes to read-only
+	;; (copy-on-write) user pages.
 	mov eax, cr0
 	or eax, 1 << 31
+	or eax, 1 << 16
 	mov cr0, eax
 
 	;; Load GDT, jump to long code.
@@ -1,8 +1,13 @@
 use core::sync::atomic::{AtomicU64, Ordering};
 
-use crate::arch::amd64::{
-	idt::{register_handler, Interrupt},
-	inb, outb,
+use crate::{
+	arch::amd64::{
+		idt::{self, register_entry, Interrupt},
+		inb, outb,
+	},
+	proc::CPU,
+	sched,
+	syscall::RegisterState,
 };
 
 const PIT_FREQ: u32 = 18;
@@ -22,7 +27,7 @@ static CLOCK: AtomicU64 = AtomicU64::new(0);
 
 pub fn init() {
 	// Set interval interrupt handler.
-	register_handler(32, handle_interval_timer);
+	register_entry(32, _interrupt_timer);
 }
 
 pub fn uptime_seconds() -> u64 {
@@ -30,10 +35,25 @@ pub fn uptime_seconds() -> u64 {
 	CLOCK.load(Ordering::Relaxed) / 18
 }
 
-pub extern "x86-interrupt" fn handle_interval_timer(_: Interrupt) {
+#[no_mangle]
+pub extern "C" fn handle_interval_timer(
+	regs: &mut RegisterState,
+	interrupt: &Interrupt,
+) {
 	// Send EOI
 	outb(0x20, 0x20);
 	CLOCK.fetch_add(1, Ordering::Relaxed);
+	idt::count(32);
+
+	// The kernel isn't preemptible, only time-slice user code.
+	if interrupt.from_user() {
+		CPU::load().current_task().register_state = *regs;
+		sched::preempt();
+	}
+}
+
+extern "C" {
+	fn _interrupt_timer();
 }
 
 fn rtc(register: u8) -> u8 {
@@ -46,6 +66,25 @@ fn bcd_rtc(register: u8) -> u8 {
 	(x & 0x0F) + ((x / 16) * 10)
 }
 
+/// Seconds since 1970-01-01 00:00, taking the RTC to run in UTC.
+pub fn unix_time() -> u64 {
+	let (year, month, day) = (year() as i64, month() as i64, day() as i64);
+
+	// Days since the epoch, with years starting in March so the leap day is
+	// the last one.
+	let year = if month <= 2 { year - 1 } else { year };
+	let era = year.div_euclid(400);
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era =
+		year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+
+	let seconds =
+		(hour() & 0x7F) as i64 * 3600 + minute() as i64 * 60 + second() as i64;
+	(days * 86400 + seconds) as u64
+}
+
 pub fn year() -> u16 {
 	(bcd_rtc(RTC_CENTURY) as u16 * 100) + bcd_rtc(RTC_YEAR) as u16
 }
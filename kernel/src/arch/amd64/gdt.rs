@@ -1,6 +1,7 @@
 use core::{
 	arch::asm,
 	fmt::{Debug, Formatter},
+	ptr,
 };
 
 #[repr(C, packed)]
@@ -31,6 +32,19 @@ pub struct FixedGDT {
 	tss: TSSDescriptor,
 }
 
+#[repr(C, packed)]
+struct TaskStateSegment {
+	reserved0: u32,
+	rsp0: u64,
+	rsp1: u64,
+	rsp2: u64,
+	reserved1: u64,
+	ist: [u64; 7],
+	reserved2: u64,
+	reserved3: u16,
+	iopb: u16,
+}
+
 extern "C" {
 	fn boot_gdt();
 	fn boot_tss();
@@ -50,6 +64,12 @@ pub fn adopt_boot_gdt() -> &'static mut FixedGDT {
 	gdt
 }
 
+/// Set the stack the CPU switches to on interrupts from ring 3.
+pub fn set_kernel_stack(rsp0: usize) {
+	let tss = boot_tss as *mut TaskStateSegment;
+	unsafe { ptr::addr_of_mut!((*tss).rsp0).write_unaligned(rsp0 as u64) };
+}
+
 impl Debug for FixedGDT {
 	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
 		writeln!(f, "0x00 {:?}", self.kernel_nl)?;
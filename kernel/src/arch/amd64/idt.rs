@@ -2,20 +2,36 @@ use core::{
 	arch::asm,
 	fmt::{Debug, Formatter},
 	mem::size_of,
+	sync::atomic::{AtomicU64, Ordering},
 };
 
-use log::{debug, warn};
+use libc::api;
+use log::{debug, info, warn};
 
 use crate::{
 	arch::amd64::{
-		cli, hlt, outb,
+		cli, hlt, outb, sti,
 		vmem::{debug_page_directory, Page, PageTable, PML4},
 	},
 	kdbg,
+	mem::vma::{Access, USER_END},
+	proc::CPU,
+	sched,
 };
 
 const MAX_INTERRUPTS: usize = 256;
 
+const RFLAGS_IF: usize = 0x1 << 9;
+
+// Page fault error code bits.
+const PF_PRESENT: usize = 0x1 << 0;
+const PF_WRITE: usize = 0x1 << 1;
+const PF_FETCH: usize = 0x1 << 4;
+
+/// Times each vector was raised, as counted by its handler.
+static COUNTS: [AtomicU64; MAX_INTERRUPTS] =
+	[const { AtomicU64::new(0) }; MAX_INTERRUPTS];
+
 static mut DESCRIPTOR_TABLE: [InterruptEntry; MAX_INTERRUPTS] =
 	[InterruptEntry::default(); MAX_INTERRUPTS];
 
@@ -71,6 +87,15 @@ impl InterruptEntry {
 }
 
 impl Interrupt {
+	pub fn from_user(&self) -> bool {
+		self.cs & 0x3 == 0x3
+	}
+
+	/// Whether the interrupted code ran with interrupts enabled.
+	pub fn interrupts_enabled(&self) -> bool {
+		self.rflags & RFLAGS_IF != 0
+	}
+
 	pub fn eoi(isr: usize) {
 		if isr > 40 {
 			outb(0xA0, 0x20);
@@ -94,7 +119,7 @@ pub fn init() {
 		register_handler_code(10, print_irq_code);
 		register_handler_code(11, print_irq_code);
 		register_handler_code(12, print_irq_code);
-		register_handler_code(13, print_irq_code);
+		register_handler_code(13, general_protection);
 		register_handler_code(14, page_fault);
 		register_handler(16, print_irq);
 		register_handler_code(17, print_irq_code);
@@ -124,17 +149,111 @@ extern "x86-interrupt" fn print_irq(interrupt: Interrupt) {
 }
 
 extern "x86-interrupt" fn invalid_opcode(interrupt: Interrupt) {
+	if interrupt.from_user() {
+		kill_current_task(api::SIGILL, "invalid opcode", &interrupt);
+	}
 	panic!("#UD({:016X}): {interrupt:#?}", interrupt.rip);
 }
 
+extern "x86-interrupt" fn general_protection(
+	interrupt: Interrupt,
+	error: usize,
+) {
+	if interrupt.from_user() {
+		kill_current_task(api::SIGSEGV, "general protection fault", &interrupt);
+	}
+	panic!(
+		"#GP({:016X}, error: {error:016X}): {interrupt:#?}",
+		interrupt.rip
+	);
+}
+
 extern "x86-interrupt" fn page_fault(interrupt: Interrupt, error: usize) {
-	debug_page_directory(PageTable::<PML4>::current_mut(), Page::USER);
+	count(14);
+	let addr = cr2();
+	let pml4 = PageTable::<PML4>::current_mut();
+
+	if error & (PF_PRESENT | PF_WRITE) == PF_PRESENT | PF_WRITE
+		&& pml4.copy_on_write(addr)
+	{
+		return;
+	}
+
+	let access = if error & PF_FETCH != 0 {
+		Access::Execute
+	} else if error & PF_WRITE != 0 {
+		Access::Write
+	} else {
+		Access::Read
+	};
+	if error & PF_PRESENT == 0
+		&& addr < USER_END
+		&& demand_page(addr, access, &interrupt)
+	{
+		return;
+	}
+
+	if interrupt.from_user() {
+		let reason = if error & PF_PRESENT != 0 {
+			"protection violation"
+		} else {
+			"not mapped"
+		};
+		info!("segfault: {access:?} at {addr:016X}, {reason}");
+		kill_current_task(api::SIGSEGV, "page fault", &interrupt);
+	}
+
+	debug_page_directory(pml4, Page::USER);
 	panic!(
-		"#PF({:016X}, error: {error:016X}): {interrupt:#?}",
+		"#PF({:016X}, addr: {addr:016X}, error: {error:016X}): {interrupt:#?}",
 		interrupt.rip
 	);
 }
 
+/// Map the page at `addr` if one of the current task's areas covers it.
+fn demand_page(addr: usize, access: Access, interrupt: &Interrupt) -> bool {
+	// Faults don't switch to the kernel GS base on their own.
+	if interrupt.from_user() {
+		unsafe { asm!("swapgs") };
+	}
+	// Filling the page from a file needs disk interrupts.
+	if interrupt.interrupts_enabled() {
+		sti();
+	}
+
+	let task = CPU::load().current_task();
+	let pml4 = PageTable::<PML4>::current_mut();
+	let mapped = task.memory.fault(pml4, addr, access).is_ok();
+
+	cli();
+	if interrupt.from_user() {
+		unsafe { asm!("swapgs") };
+	}
+	mapped
+}
+
+/// Terminate the current task because of a fault it caused in user space, its
+/// parent sees it killed by `signal`.
+fn kill_current_task(signal: i32, fault: &str, interrupt: &Interrupt) -> ! {
+	cli();
+	// Faults don't switch to the kernel GS base on their own.
+	unsafe { asm!("swapgs") };
+
+	let task = CPU::load().current_task();
+	info!(
+		"process {} killed by signal {signal}: {fault} at {:016X}",
+		task.pid, interrupt.rip
+	);
+
+	sched::exit(signal)
+}
+
+fn cr2() -> usize {
+	let cr2: usize;
+	unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+	cr2
+}
+
 extern "x86-interrupt" fn breakpoint(interrupt: Interrupt) {
 	warn!("#BP({:016X}): {interrupt:#?}", interrupt.rip);
 	cli();
@@ -145,6 +264,20 @@ extern "x86-interrupt" fn print_irq_code(interrupt: Interrupt, error: usize) {
 	panic!("{interrupt:#?} {error:#?}");
 }
 
+/// Count one more `irq`, for /proc/interrupts.
+pub fn count(irq: usize) {
+	COUNTS[irq].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Vectors that were raised at least once and how often.
+pub fn counts() -> impl Iterator<Item = (usize, u64)> {
+	COUNTS
+		.iter()
+		.map(|count| count.load(Ordering::Relaxed))
+		.enumerate()
+		.filter(|&(_, count)| count != 0)
+}
+
 pub fn register_handler(
 	irq: usize,
 	handler: extern "x86-interrupt" fn(Interrupt),
@@ -171,6 +304,17 @@ pub fn register_handler_code(
 	}
 }
 
+/// Register an assembly entry stub that handles its own register saving (see
+/// interrupt.asm).
+pub fn register_entry(irq: usize, entry: unsafe extern "C" fn()) {
+	unsafe {
+		let flags = if irq < 14 { 0x8F } else { 0x8E };
+		DESCRIPTOR_TABLE[irq] =
+			// TODO: left shift by 8 is a hack porting from x86.
+			InterruptEntry::new(entry as usize, 0x08, flags << 8);
+	}
+}
+
 unsafe fn flush_idt() {
 	let idtr = kdbg!(IDTR::new(&DESCRIPTOR_TABLE));
 	asm!("lidt [rax]", in("rax") &idtr);
@@ -0,0 +1,59 @@
+	bits 64
+
+	extern handle_interval_timer
+	global _interrupt_timer
+
+	section .text
+	;; Builds a RegisterState (see syscall.rs) below the interrupt frame and
+	;; calls the handler with (rdi: &mut RegisterState, rsi: &Interrupt).
+_interrupt_timer:
+	;; Swap to the kernel GS base if we interrupted user space.
+	test qword [rsp + 8], 3 ; cs
+	jz .kernel_entry
+	swapgs
+.kernel_entry:
+	push qword [rsp] ; rip
+	push r15
+	push r14
+	push r13
+	push r12
+	push r11
+	push r10
+	push r9
+	push r8
+	push rax
+	push rcx
+	push rdx
+	push rbx
+	push qword [rsp + 13 * 8 + 24] ; interrupted rsp
+	push rbp
+	push rsi
+	push rdi
+
+	mov rdi, rsp
+	lea rsi, [rsp + 17 * 8]
+	call handle_interval_timer
+
+	pop rdi
+	pop rsi
+	pop rbp
+	add rsp, 8
+	pop rbx
+	pop rdx
+	pop rcx
+	pop rax
+	pop r8
+	pop r9
+	pop r10
+	pop r11
+	pop r12
+	pop r13
+	pop r14
+	pop r15
+	add rsp, 8 ; rip
+
+	test qword [rsp + 8], 3 ; cs
+	jz .kernel_exit
+	swapgs
+.kernel_exit:
+	iretq
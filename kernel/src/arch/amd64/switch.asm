@@ -0,0 +1,26 @@
+	bits 64
+
+	global switch_context
+
+	section .text
+	;; (rdi: *mut usize, rsi: usize)
+	;; Saves callee-saved registers on the current kernel stack, stores the
+	;; stack pointer in [rdi] and resumes the kernel stack in rsi.
+switch_context:
+	push rbp
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+
+	mov [rdi], rsp
+	mov rsp, rsi
+
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	pop rbp
+	ret
@@ -2,14 +2,13 @@
 
 	extern syscall_enter
 	global _syscall_enter
-	global _syscall_ret
+	global _syscall_exit
 
 	section .text
 _syscall_enter:
 	swapgs
 	mov [gs:0 + 8], rsp
 	mov rsp, [gs:0]
-	sti
 
 	push rcx ; rip
 	push r15
@@ -24,18 +23,22 @@ _syscall_enter:
 	push rcx
 	push rdx
 	push rbx
-	push rsp
+	push qword [gs:0 + 8] ; user rsp
 	push rbp
 	push rsi
 	push rdi
+	sti
 
 	mov rdi, rsp
 	call syscall_enter
 
+	;; New tasks start here with a RegisterState on top of their kernel stack.
+_syscall_exit:
+	cli
 	pop rdi
 	pop rsi
 	pop rbp
-	add rsp, 8
+	pop qword [gs:0 + 8] ; user rsp
 	pop rbx
 	pop rdx
 	pop rcx
@@ -49,11 +52,8 @@ _syscall_enter:
 	pop r14
 	pop r15
 	pop rcx ; rip
-_syscall_ret:
-	cli
-	rdgsbase r11
-	mov [r11], rsp
-	mov rsp, [r11 + 8]
+
+	mov rsp, [gs:0 + 8]
 	mov r11, 0x202
 	swapgs
 	o64 sysret
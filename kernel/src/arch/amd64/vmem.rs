@@ -1,4 +1,4 @@
-use alloc::alloc::alloc_zeroed;
+use alloc::alloc::{alloc_zeroed, dealloc};
 use core::{
 	alloc::Layout,
 	arch::asm,
@@ -9,7 +9,10 @@ use core::{
 
 use log::{debug, warn};
 
-use crate::mem::{frame, PhysicalAddress, KERNEL_VMA, PAGE_SIZE};
+use crate::mem::{
+	frame, frame::OutOfMemory, PhysicalAddress, HUGE_PAGE_SIZE, KERNEL_VMA,
+	PAGE_SIZE,
+};
 
 pub trait Table {
 	fn index_of(addr: usize) -> usize;
@@ -63,6 +66,39 @@ impl Table for PD {
 	}
 }
 
+impl PageTableDirectory for PD {
+	type NextTable = PT;
+}
+
+pub enum PT {}
+
+impl Table for PT {
+	fn index_of(addr: usize) -> usize {
+		(addr >> 12) & 0x1FF
+	}
+
+	fn name() -> &'static str {
+		"PT"
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PageSize {
+	/// 4 KiB, mapped by a PT entry.
+	Small,
+	/// 2 MiB, mapped directly by a PD entry.
+	Huge,
+}
+
+impl PageSize {
+	pub fn bytes(&self) -> usize {
+		match self {
+			PageSize::Small => PAGE_SIZE,
+			PageSize::Huge => HUGE_PAGE_SIZE,
+		}
+	}
+}
+
 #[repr(transparent)]
 #[derive(Copy, Clone)]
 pub struct Page(u64);
@@ -72,6 +108,10 @@ impl Page {
 	pub const READ_WRITE: u64 = 0x1 << 1;
 	pub const USER: u64 = 0x1 << 2;
 	pub const HUGE: u64 = 0x1 << 7;
+	/// Available to software: read-only because the frame is shared.
+	pub const COPY_ON_WRITE: u64 = 0x1 << 9;
+
+	const ADDRESS_MASK: u64 = 0x000FFFFFFFFFF000;
 
 	pub fn new(addr: PhysicalAddress, flags: u64) -> Self {
 		assert_eq!(addr.0 % 0x1000, 0);
@@ -84,7 +124,7 @@ impl Page {
 	}
 
 	pub fn set(&mut self, flags: u64) {
-		self.0 &= flags;
+		self.0 |= flags;
 	}
 
 	pub fn unset(&mut self, flags: u64) {
@@ -92,11 +132,11 @@ impl Page {
 	}
 
 	pub fn address(&self) -> PhysicalAddress {
-		PhysicalAddress((self.0 & !0x1FF) as usize)
+		PhysicalAddress((self.0 & Self::ADDRESS_MASK) as usize)
 	}
 
 	pub fn flags(&self) -> u64 {
-		self.0 & 0x1FF
+		self.0 & 0xFFF
 	}
 }
 
@@ -108,26 +148,111 @@ pub struct PageTable<L: Table> {
 }
 
 impl PageTable<PML4> {
-	pub fn map(&mut self, phys: usize, virt: usize, flags: u64, invlpg: bool) {
-		assert_eq!(phys % PAGE_SIZE, 0);
-		assert_eq!(virt % PAGE_SIZE, 0);
-
-		// TODO: All pages are huge.
+	pub fn map(
+		&mut self,
+		phys: usize,
+		virt: usize,
+		size: PageSize,
+		flags: u64,
+		invlpg: bool,
+	) -> Result<(), OutOfMemory> {
+		assert_eq!(phys % size.bytes(), 0);
+		assert_eq!(virt % size.bytes(), 0);
+
+		// Directories don't restrict access, the leaf entries do.
+		let table_flags =
+			Page::PRESENT | Page::READ_WRITE | (flags & Page::USER);
 		let pd = self
-			.next_alloc(PML4::index_of(virt), flags & !Page::HUGE)
-			.next_alloc(PDP::index_of(virt), flags & !Page::HUGE);
-
-		if pd.entries[PD::index_of(virt)].has(Page::PRESENT) {
+			.next_alloc(PML4::index_of(virt), table_flags)?
+			.next_alloc(PDP::index_of(virt), table_flags)?;
+		let (entry, flags) = match size {
+			PageSize::Huge => (&mut pd[PD::index_of(virt)], flags | Page::HUGE),
+			PageSize::Small => (
+				&mut pd.next_alloc(PD::index_of(virt), table_flags)?
+					[PT::index_of(virt)],
+				flags & !Page::HUGE,
+			),
+		};
+
+		if entry.has(Page::PRESENT) {
 			warn!("Not mapping present page P: {phys:016X?}, V: {virt:016X?}");
-			return;
+			return Ok(());
 		}
 
-		pd.entries[PD::index_of(virt)] =
-			Page::new(PhysicalAddress(phys), flags | Page::HUGE);
+		*entry = Page::new(PhysicalAddress(phys), flags);
 
 		if invlpg {
 			unsafe { asm!("invlpg [{}]", in(reg) virt) };
 		}
+		Ok(())
+	}
+
+	/// Map a zeroed frame at `virt`, unless a page is mapped there already.
+	pub fn map_zeroed(
+		&mut self,
+		virt: usize,
+		flags: u64,
+	) -> Result<(), OutOfMemory> {
+		if self
+			.entry_mut(virt)
+			.is_some_and(|page| page.has(Page::PRESENT))
+		{
+			return Ok(());
+		}
+
+		let frame = frame::current_mut().lock().alloc()?;
+		unsafe { ptr::write_bytes(frame.to_virtual::<u8>(), 0, PAGE_SIZE) };
+		if let Err(error) =
+			self.map(frame.0, virt, PageSize::Small, flags, true)
+		{
+			frame::current_mut().lock().release(frame);
+			return Err(error);
+		}
+		Ok(())
+	}
+
+	/// The huge PD entry or PT entry `virt` falls into.
+	pub fn entry_mut(&mut self, virt: usize) -> Option<&mut Page> {
+		let pd = self
+			.next_mut(PML4::index_of(virt))?
+			.next_mut(PDP::index_of(virt))?;
+
+		let i = PD::index_of(virt);
+		if pd[i].has(Page::PRESENT | Page::HUGE) {
+			return Some(&mut pd[i]);
+		}
+
+		Some(&mut pd.next_mut(i)?[PT::index_of(virt)])
+	}
+
+	/// Remove the 4 KiB page at `virt`, returns the frame it mapped.
+	pub fn unmap(&mut self, virt: usize) -> Option<PhysicalAddress> {
+		let entry = self.entry_mut(virt)?;
+		if !entry.has(Page::PRESENT) {
+			return None;
+		}
+
+		let frame = entry.address();
+		*entry = Page::new(PhysicalAddress(0), 0);
+		unsafe { asm!("invlpg [{}]", in(reg) virt) };
+		Some(frame)
+	}
+
+	/// Change the flags of the 4 KiB page at `virt`. Pages that become
+	/// writable are made copy-on-write instead, their frame might be shared.
+	pub fn protect(&mut self, virt: usize, mut flags: u64) {
+		let Some(entry) = self.entry_mut(virt) else {
+			return;
+		};
+		if !entry.has(Page::PRESENT) {
+			return;
+		}
+
+		if flags & Page::READ_WRITE != 0 && !entry.has(Page::READ_WRITE) {
+			flags = (flags & !Page::READ_WRITE) | Page::COPY_ON_WRITE;
+		}
+		*entry = Page::new(entry.address(), flags);
+		unsafe { asm!("invlpg [{}]", in(reg) virt) };
 	}
 
 	pub fn current_mut() -> &'static mut PageTable<PML4> {
@@ -138,9 +263,9 @@ impl PageTable<PML4> {
 		}
 	}
 
-	pub fn new_with_kernel() -> &'static mut Self {
+	pub fn new_with_kernel() -> Result<&'static mut Self, OutOfMemory> {
 		let current = Self::current_mut();
-		let new = unsafe { &mut *(Self::alloc() as *mut Self) };
+		let new = unsafe { &mut *(Self::alloc()? as *mut Self) };
 
 		for i in 0..current.entries.len() {
 			let entry = current.entries[i];
@@ -149,14 +274,105 @@ impl PageTable<PML4> {
 			}
 		}
 
-		new
+		Ok(new)
 	}
 
-	pub fn fork(&self) -> &'static mut Self {
-		let new = Self::new_with_kernel();
-		copy_user_page_directories(self, new);
-		new
+	/// Copy the user half of this address space. User pages are shared
+	/// read-only between both and copied on the first write (see
+	/// `copy_on_write()`).
+	pub fn fork(&mut self) -> Result<&'static mut Self, OutOfMemory> {
+		let new = Self::new_with_kernel()?;
+		let copied = copy_user_page_directories(self, new);
+		// Writable pages in this table were just made read-only.
+		flush_tlb();
+		if let Err(error) = copied {
+			new.free();
+			return Err(error);
+		}
+		Ok(new)
+	}
+
+	/// Resolve a write fault on a copy-on-write page, returns false if `virt`
+	/// isn't mapped copy-on-write or there is no memory left to copy it.
+	pub fn copy_on_write(&mut self, virt: usize) -> bool {
+		let Some(entry) = self.entry_mut(virt) else {
+			return false;
+		};
+		// Only 4 KiB user pages are ever copy-on-write.
+		if !entry.has(Page::PRESENT | Page::COPY_ON_WRITE) {
+			return false;
+		}
+
+		let frame = entry.address();
+		let flags = (entry.flags() | Page::READ_WRITE) & !Page::COPY_ON_WRITE;
+		let mut falloc = frame::current_mut().lock();
+
+		// The last sharer can take the frame as is.
+		if falloc.refs(frame) == 1 {
+			*entry = Page::new(frame, flags);
+		} else {
+			let Ok(copy) = falloc.alloc() else {
+				return false;
+			};
+			unsafe {
+				ptr::copy_nonoverlapping(
+					frame.to_virtual::<u8>(),
+					copy.to_virtual(),
+					PAGE_SIZE,
+				);
+			}
+			falloc.release(frame);
+			*entry = Page::new(copy, flags);
+		}
+
+		unsafe { asm!("invlpg [{}]", in(reg) virt) };
+		true
 	}
+
+	/// Free this table, the user tables below it and release the frames they
+	/// map. Must not be the current page table.
+	pub fn free(&mut self) {
+		for i in 0..self.entries.len() {
+			if !self.entries[i].has(Page::PRESENT | Page::USER) {
+				continue;
+			}
+			let pdp = self.next_mut(i).unwrap();
+			for i in 0..pdp.entries.len() {
+				if !pdp.entries[i].has(Page::PRESENT | Page::USER) {
+					continue;
+				}
+				let pd = pdp.next_mut(i).unwrap();
+				for i in 0..pd.entries.len() {
+					if !pd.entries[i].has(Page::PRESENT | Page::USER) {
+						continue;
+					}
+					let pt = pd.next_mut(i).unwrap();
+					// Tables come from the heap, which takes this lock too.
+					let mut falloc = frame::current_mut().lock();
+					for i in 0..pt.entries.len() {
+						if pt.entries[i].has(Page::PRESENT) {
+							falloc.release(pt.entries[i].address());
+						}
+					}
+					drop(falloc);
+					pt.dealloc();
+				}
+				pd.dealloc();
+			}
+			pdp.dealloc();
+		}
+		self.dealloc();
+	}
+}
+
+pub fn flush_tlb() {
+	unsafe {
+		asm!(
+			"mov {0}, cr3",
+			"mov cr3, {0}",
+			out(reg) _,
+		)
+	};
 }
 
 // TODO: Needs more nesting.
@@ -189,6 +405,20 @@ pub fn debug_page_directory(dir: &PageTable<PML4>, filter: u64) {
 								entry.address().0,
 								entry.flags()
 							);
+							let Some(pt) = pd.next(i) else {
+								continue;
+							};
+
+							for i in 0..pt.entries.len() {
+								let entry = pt.entries[i];
+								if entry.has(filter) {
+									debug!(
+										"            [{i:03}] {:016X}:{:03X}",
+										entry.address().0,
+										entry.flags()
+									);
+								}
+							}
 						}
 					}
 				}
@@ -199,39 +429,49 @@ pub fn debug_page_directory(dir: &PageTable<PML4>, filter: u64) {
 
 // TODO: Needs more nesting.
 fn copy_user_page_directories(
-	src: &PageTable<PML4>,
+	src: &mut PageTable<PML4>,
 	dst: &mut PageTable<PML4>,
-) {
+) -> Result<(), OutOfMemory> {
 	for i in 0..src.entries.len() {
 		if src.entries[i].has(Page::PRESENT | Page::USER) {
-			let pdp_new = dst.next_alloc(i, 7);
-			let pdp_cur = src.next(i).unwrap();
+			let pdp_new = dst.next_alloc(i, 7)?;
+			let pdp_cur = src.next_mut(i).unwrap();
 
 			for i in 0..pdp_cur.entries.len() {
 				if pdp_cur.entries[i].has(Page::PRESENT | Page::USER) {
-					let pd_new = pdp_new.next_alloc(i, 7);
-					let pd_cur = pdp_cur.next(i).unwrap();
+					let pd_new = pdp_new.next_alloc(i, 7)?;
+					let pd_cur = pdp_cur.next_mut(i).unwrap();
 
-					copy_user_page_table(pd_cur, pd_new);
+					for i in 0..pd_cur.entries.len() {
+						if pd_cur.entries[i].has(Page::PRESENT | Page::USER) {
+							let pt_new = pd_new.next_alloc(i, 7)?;
+							let pt_cur = pd_cur.next_mut(i).unwrap();
+
+							share_user_pages(pt_cur, pt_new);
+						}
+					}
 				}
 			}
 		}
 	}
+	Ok(())
 }
 
-fn copy_user_page_table(src: &PageTable<PD>, dst: &mut PageTable<PD>) {
+fn share_user_pages(src: &mut PageTable<PT>, dst: &mut PageTable<PT>) {
 	let mut falloc = frame::current_mut().lock();
 	for i in 0..src.entries.len() {
-		if src.entries[i].has(Page::PRESENT | Page::USER) {
-			dst[i] = Page::new(falloc.alloc(), 0x87);
-			unsafe {
-				ptr::copy_nonoverlapping(
-					src.entries[i].address().to_virtual() as *const u8,
-					dst[i].address().to_virtual(),
-					PAGE_SIZE,
-				);
-			}
+		// Inaccessible (PROT_NONE) user pages are mapped without USER.
+		let entry = &mut src.entries[i];
+		if !entry.has(Page::PRESENT) {
+			continue;
 		}
+
+		if entry.has(Page::READ_WRITE) {
+			entry.unset(Page::READ_WRITE);
+			entry.set(Page::COPY_ON_WRITE);
+		}
+		dst[i] = *entry;
+		falloc.share(entry.address());
 	}
 }
 
@@ -240,8 +480,16 @@ impl<L: Table> PageTable<L> {
 		L::index_of(addr)
 	}
 
-	fn alloc() -> usize {
-		unsafe { alloc_zeroed(Layout::new::<Self>()) as *mut _ as usize }
+	fn alloc() -> Result<usize, OutOfMemory> {
+		let table = unsafe { alloc_zeroed(Layout::new::<Self>()) };
+		if table.is_null() {
+			return Err(OutOfMemory);
+		}
+		Ok(table as usize)
+	}
+
+	fn dealloc(&mut self) {
+		unsafe { dealloc(self as *mut Self as *mut u8, Layout::new::<Self>()) }
 	}
 }
 
@@ -261,7 +509,9 @@ impl<L: Table> IndexMut<usize> for PageTable<L> {
 
 impl<L: PageTableDirectory> PageTable<L> {
 	pub fn next(&self, idx: usize) -> Option<&PageTable<L::NextTable>> {
-		if self.entries[idx].has(Page::PRESENT) {
+		if self.entries[idx].has(Page::PRESENT)
+			&& !self.entries[idx].has(Page::HUGE)
+		{
 			Some(unsafe { &mut *self.entries[idx].address().to_virtual() })
 		} else {
 			None
@@ -272,7 +522,9 @@ impl<L: PageTableDirectory> PageTable<L> {
 		&mut self,
 		idx: usize,
 	) -> Option<&mut PageTable<L::NextTable>> {
-		if self.entries[idx].has(Page::PRESENT) {
+		if self.entries[idx].has(Page::PRESENT)
+			&& !self.entries[idx].has(Page::HUGE)
+		{
 			Some(unsafe { &mut *self.entries[idx].address().to_virtual() })
 		} else {
 			None
@@ -283,9 +535,9 @@ impl<L: PageTableDirectory> PageTable<L> {
 		&mut self,
 		idx: usize,
 		flags: u64,
-	) -> &mut PageTable<L::NextTable> {
+	) -> Result<&mut PageTable<L::NextTable>, OutOfMemory> {
 		if self.entries[idx].has(Page::PRESENT) {
-			return self.next_mut(idx).unwrap();
+			return Ok(self.next_mut(idx).unwrap());
 		}
 
 		debug!(
@@ -294,33 +546,36 @@ impl<L: PageTableDirectory> PageTable<L> {
 			idx
 		);
 
-		let addr = Self::alloc();
+		let addr = Self::alloc()?;
 		self.entries[idx] =
 			Page::new(PhysicalAddress(addr - KERNEL_VMA), flags & !Page::HUGE);
 
-		self.next_mut(idx).unwrap()
+		Ok(self.next_mut(idx).unwrap())
 	}
 }
 
-pub fn page_table_index(vaddr: usize) -> (usize, usize, usize) {
+pub fn page_table_index(vaddr: usize) -> (usize, usize, usize, usize) {
 	(
 		PML4::index_of(vaddr),
 		PDP::index_of(vaddr),
 		PD::index_of(vaddr),
+		PT::index_of(vaddr),
 	)
 }
 
 pub fn map_physical_memory(size: usize) {
-	assert_eq!(size % PAGE_SIZE, 0);
+	assert_eq!(size % HUGE_PAGE_SIZE, 0);
 
 	let pml4 = PageTable::<PML4>::current_mut();
 
-	for addr in (0..size).step_by(PAGE_SIZE) {
+	for addr in (0..size).step_by(HUGE_PAGE_SIZE) {
 		pml4.map(
 			addr,
 			PhysicalAddress(addr).to_virtual_addr(),
+			PageSize::Huge,
 			Page::PRESENT | Page::READ_WRITE,
 			true,
-		);
+		)
+		.expect("out of memory mapping physical memory");
 	}
 }
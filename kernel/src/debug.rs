@@ -17,6 +17,7 @@ macro_rules! kdbg {
 	};
 }
 
+#[allow(unused_macros)]
 macro_rules! breakpoint {
 	() => {
 		unsafe { ::core::arch::asm!("int 3") }
@@ -5,7 +5,7 @@ use log::trace;
 
 use crate::{
 	arch::amd64::{
-		idt::{register_handler, Interrupt},
+		idt::{self, register_handler, Interrupt},
 		inb, insl, outb, outsl,
 	},
 	sync::RacyCell,
@@ -17,6 +17,7 @@ const IDE_BSY: u8 = 0x80;
 const IDE_DRDY: u8 = 0x40;
 const IDE_ERR: u8 = 0x01;
 const IDE_DF: u8 = 0x20;
+const IDE_DRQ: u8 = 0x08;
 const IDE_CMD_READ: u8 = 0x20;
 const IDE_CMD_WRITE: u8 = 0x30;
 const IDE_CMD_FLUSH: u8 = 0xE7;
@@ -38,6 +39,19 @@ pub fn ide_wait() {
 	}
 }
 
+/// Whether a drive answers as `device`, reads from a missing one wait for it
+/// forever.
+pub fn is_present(device: u8) -> bool {
+	outb(0x1F6, LBA_MODE | lba(device));
+	// The status is valid 400ns after selecting, reading it takes 100ns.
+	for _ in 0..4 {
+		inb(0x1F7);
+	}
+	// A missing drive reads as 0, an empty channel floats to 0xFF.
+	let status = inb(0x1F7);
+	status != 0 && status != 0xFF
+}
+
 pub fn init() {
 	trace!("ide::init()");
 	register_handler(46, ide_isr);
@@ -77,6 +91,7 @@ fn write_sector(device: u8, sector: usize, src: usize) {
 	outb(0x1F5, (sector >> 16) as u8); // 16..24 bits of LBA.
 
 	outb(0x1F7, IDE_CMD_WRITE); // Send write command.
+	while inb(0x1F7) & IDE_DRQ == 0 { /* SPIN WAIT */ }
 	outsl(SECTOR_SIZE / 4, src, 0x1F0);
 
 	ide_wait();
@@ -89,9 +104,9 @@ pub fn read(
 	dst: *mut u8,
 	len: usize,
 ) {
-	let num_sectors = len.div_ceil(SECTOR_SIZE);
 	let start_sector = start_sector + (read_offset as u32 / SECTOR_SIZE as u32);
 	let read_offset = read_offset % SECTOR_SIZE;
+	let num_sectors = (read_offset + len).div_ceil(SECTOR_SIZE);
 
 	let mut bytes_read = 0;
 	let read_len = min(SECTOR_SIZE - read_offset, len - bytes_read);
@@ -111,6 +126,41 @@ pub fn read(
 	}
 }
 
+/// Write `len` bytes from `src` at `write_offset` bytes into the disk starting
+/// at `start_sector`. Partly written sectors are read first.
+pub fn write(
+	device: u8,
+	start_sector: u32,
+	write_offset: usize,
+	src: *const u8,
+	len: usize,
+) {
+	let mut sector = start_sector + (write_offset / SECTOR_SIZE) as u32;
+	let mut offset = write_offset % SECTOR_SIZE;
+	let mut buf = [0u8; SECTOR_SIZE];
+
+	let mut written = 0;
+	while written < len {
+		let write_len = min(SECTOR_SIZE - offset, len - written);
+		if write_len < SECTOR_SIZE {
+			read_sector(device, sector, 1);
+			read_buffer(0, SECTOR_SIZE, &mut buf);
+		}
+		unsafe {
+			ptr::copy_nonoverlapping(
+				src.add(written),
+				buf.as_mut_ptr().add(offset),
+				write_len,
+			)
+		};
+		write_sector(device, sector as usize, buf.as_ptr() as usize);
+
+		written += write_len;
+		sector += 1;
+		offset = 0;
+	}
+}
+
 pub fn read_type<T>(device: u8, start_sector: u32) -> Box<T> {
 	let layout = Layout::new::<T>();
 	let buf = unsafe { alloc(layout) };
@@ -176,8 +226,12 @@ fn read_buffer(offset: usize, len: usize, buf: &mut [u8]) {
 
 extern "x86-interrupt" fn ide_isr(int: Interrupt) {
 	trace!("IDE INTERRUPT: {int:#?}");
-	let buf =
-		unsafe { BUFFER.get_mut() }.as_ref() as *const _ as *mut u8 as usize;
-	insl(buf, SECTOR_SIZE / 4, 0x1F0);
+	idt::count(46);
+	// Writes interrupt too, with nothing to read.
+	if inb(0x1F7) & IDE_DRQ != 0 {
+		let buf = unsafe { BUFFER.get_mut() }.as_ref() as *const _ as *mut u8
+			as usize;
+		insl(buf, SECTOR_SIZE / 4, 0x1F0);
+	}
 	Interrupt::eoi(46);
 }
@@ -1,6 +1,6 @@
 use crate::{
 	arch::amd64::{
-		idt::{register_handler, Interrupt},
+		idt::{self, register_handler, Interrupt},
 		inb, outb,
 	},
 	devices::character::{Keycode, ReadCharacter},
@@ -107,6 +107,7 @@ fn is_key_down(scan_code: u8) -> bool {
 }
 
 extern "x86-interrupt" fn irq_handler(_: Interrupt) {
+	idt::count(0x21);
 	let mut kbd = unsafe { KBD.get_mut() };
 	while keyboard_has_data() {
 		match keyboard_read_scan_code() {
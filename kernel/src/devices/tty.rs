@@ -2,12 +2,12 @@ use alloc::string::String;
 use core::fmt::Write;
 
 use crate::{
-	arch::amd64::hlt,
 	devices::{
 		character::{Keycode, ReadCharacter, WriteCharacter},
 		keyboard::KBD,
 		vga::vga0,
 	},
+	sched,
 	sync::{SpinLock, StaticPtr},
 };
 
@@ -23,33 +23,36 @@ pub fn tty0() -> &'static mut SpinLock<Terminal> {
 
 pub struct Terminal;
 
-impl Terminal {
-	pub fn read_line(&mut self) -> String {
+impl SpinLock<Terminal> {
+	/// Only holds the lock while handling a key, so other tasks can write to
+	/// the terminal while this one waits for input.
+	pub fn read_line(&self) -> String {
 		let mut s = String::with_capacity(80);
 		loop {
-			match self.getc() {
+			let keycode = self.lock().getc();
+			match keycode {
 				Some(Keycode::Backspace) => {
 					if s.pop().is_some() {
-						self.putc(Keycode::Backspace);
+						self.lock().putc(Keycode::Backspace);
 					}
 				}
 				Some(Keycode::Nak) => {
 					while s.pop().is_some() {
-						self.putc(Keycode::Backspace)
+						self.lock().putc(Keycode::Backspace)
 					}
 				}
 				Some(Keycode::Char(c)) => {
 					s.write_char(c).unwrap();
-					self.putc(Keycode::Char(c));
+					self.lock().putc(Keycode::Char(c));
 				}
 				Some(Keycode::Newline) => {
-					self.putc(Keycode::Newline);
+					self.lock().putc(Keycode::Newline);
 					break;
 				}
-				Some(kc) => self.putc(kc),
+				Some(kc) => self.lock().putc(kc),
 				_ => {}
 			}
-			hlt();
+			sched::yield_now();
 		}
 		s
 	}
@@ -9,12 +9,12 @@ const CR: u8 = '\r' as u8;
 const TAB: u8 = '\t' as u8;
 
 use crate::{
-	arch::amd64::hlt,
 	devices::{
 		character::{Keycode, ReadCharacter, WriteCharacter},
 		keyboard::KBD,
 		video::vd0,
 	},
+	sched,
 	sync::StaticPtr,
 };
 
@@ -77,7 +77,7 @@ impl VideoTerminal {
 				_ => continue,
 			}
 			self.blit();
-			hlt();
+			sched::yield_now();
 		}
 		s
 	}
@@ -1,50 +1,74 @@
-use core::{ptr, slice};
+use alloc::{vec, vec::Vec};
+use core::{
+	cmp::min,
+	mem::{size_of, MaybeUninit},
+	slice,
+};
 
+use libc::api;
 use log::debug;
 
-use crate::proc::Task;
+use crate::{
+	arch::amd64::vmem::{PageTable, PML4},
+	fs::FileDescriptor,
+	mem::{
+		vma::{Area, Backing, MapError, USER_END},
+		PAGE_SIZE,
+	},
+	proc::Task,
+};
 
 const EI_NIDENT: usize = 16;
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
 
-#[repr(u32)]
-#[derive(Debug)]
-enum ProgramHeaderType {
-	Null,
-	Load,
-	Dynamic,
-	Interp,
-	Note,
-	ShLib,
-	PHDR,
-	LoProc = 0x70000000,
-	HiProc = 0x7FFFFFFF,
-}
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
 
-#[repr(u16)]
 #[derive(Debug)]
-enum ExecutableType {
-	None,
-	Rel,
-	Exec,
-	Dyn,
-	Core,
-	LoProc = 0xFF00,
-	HiProc = 0xFFFF,
+pub enum ElfError {
+	/// Not an ELF file at all.
+	BadMagic,
+	/// Not a little endian 64-bit ELF file.
+	BadClass,
+	/// Not built for x86-64.
+	BadMachine(u16),
+	/// Not a static executable.
+	BadType(u16),
+	/// Headers or segments point past the end of the file.
+	Truncated,
+	/// A segment outside user space, overlapping another one or with more
+	/// file than memory size.
+	BadSegment(usize),
+	Map(MapError),
 }
 
-#[repr(u32)]
-enum ProgramHeaderFlags {
-	X = 0x1,
-	W = 0x2,
-	R = 0x4,
+impl From<MapError> for ElfError {
+	fn from(error: MapError) -> Self {
+		ElfError::Map(error)
+	}
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 struct ELF64Header {
 	e_ident: [u8; EI_NIDENT],
-	// TODO: No panics on malformed ELF headers.
-	e_type: ExecutableType,
+	e_type: u16,
 	e_machine: u16,
 	e_version: u32,
 	e_entry: u64,
@@ -60,10 +84,9 @@ struct ELF64Header {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 struct ELF64ProgramHeader {
-	// TODO: No panics on malformed ELF headers.
-	p_type: ProgramHeaderType,
+	p_type: u32,
 	p_flags: u32,
 	p_offset: u64,
 	p_vaddr: u64,
@@ -73,29 +96,241 @@ struct ELF64ProgramHeader {
 	p_align: u64,
 }
 
-pub fn load(elf: *const u8, task: &mut Task) {
-	let header: &ELF64Header = unsafe { &*(elf as *const ELF64Header) };
-	debug!("{header:#?}");
-	let program_headers: &[ELF64ProgramHeader] = unsafe {
-		slice::from_raw_parts(
-			elf.offset(header.e_phoff as isize) as *const ELF64ProgramHeader,
-			header.e_phnum as usize,
-		)
-	};
-
-	for phdr in program_headers
-		.iter()
-		.filter(|phdr| matches!(phdr.p_type, ProgramHeaderType::Load))
-	{
-		debug!("{phdr:#X?}");
-		unsafe {
-			ptr::copy_nonoverlapping(
-				elf.offset(phdr.p_offset as isize),
-				phdr.p_vaddr as usize as *mut u8,
-				phdr.p_filesz as usize,
+impl ELF64ProgramHeader {
+	fn prot(&self) -> i32 {
+		let mut prot = api::PROT_NONE;
+		if self.p_flags & PF_R != 0 {
+			prot |= api::PROT_READ;
+		}
+		if self.p_flags & PF_W != 0 {
+			prot |= api::PROT_WRITE;
+		}
+		if self.p_flags & PF_X != 0 {
+			prot |= api::PROT_EXEC;
+		}
+		prot
+	}
+
+	fn vaddr(&self) -> usize {
+		self.p_vaddr as usize
+	}
+}
+
+/// Where an executable is read from.
+pub enum ElfSource<'a> {
+	Memory(&'a [u8]),
+	File(FileDescriptor),
+}
+
+impl ElfSource<'_> {
+	fn len(&self) -> usize {
+		match self {
+			ElfSource::Memory(data) => data.len(),
+			ElfSource::File(file) => file.inode.stat().size,
+		}
+	}
+
+	/// Fill `buf` from `offset`, short reads fail.
+	fn read_at(
+		&mut self,
+		offset: usize,
+		buf: &mut [u8],
+	) -> Result<(), ElfError> {
+		let len = match self {
+			ElfSource::Memory(data) => {
+				let src = data.get(offset..).unwrap_or_default();
+				let len = min(src.len(), buf.len());
+				buf[..len].copy_from_slice(&src[..len]);
+				len
+			}
+			ElfSource::File(file) => {
+				file.pread(offset, buf.as_mut_ptr(), buf.len())
+			}
+		};
+
+		if len < buf.len() {
+			return Err(ElfError::Truncated);
+		}
+		Ok(())
+	}
+
+	fn read<T: Copy>(&mut self, offset: usize) -> Result<T, ElfError> {
+		let mut value = MaybeUninit::<T>::uninit();
+		let buf = unsafe {
+			slice::from_raw_parts_mut(
+				value.as_mut_ptr() as *mut u8,
+				size_of::<T>(),
 			)
+		};
+		self.read_at(offset, buf)?;
+		Ok(unsafe { value.assume_init() })
+	}
+}
+
+/// A validated executable, nothing in it can make `load()` fail but running
+/// out of memory or the file shrinking.
+pub struct Elf<'a> {
+	source: ElfSource<'a>,
+	header: ELF64Header,
+	/// The `PT_LOAD` program headers.
+	segments: Vec<ELF64ProgramHeader>,
+}
+
+impl<'a> Elf<'a> {
+	/// Read and check the headers, segments are only read by `load()`.
+	pub fn parse(mut source: ElfSource<'a>) -> Result<Self, ElfError> {
+		if source.len() < size_of::<ELF64Header>() {
+			return Err(ElfError::BadMagic);
+		}
+		let header: ELF64Header = source.read(0)?;
+		debug!("{header:#?}");
+
+		if header.e_ident[..ELF_MAGIC.len()] != ELF_MAGIC {
+			return Err(ElfError::BadMagic);
+		}
+		if header.e_ident[EI_CLASS] != ELFCLASS64
+			|| header.e_ident[EI_DATA] != ELFDATA2LSB
+		{
+			return Err(ElfError::BadClass);
+		}
+		if header.e_machine != EM_X86_64 {
+			return Err(ElfError::BadMachine(header.e_machine));
+		}
+		if header.e_type != ET_EXEC {
+			return Err(ElfError::BadType(header.e_type));
+		}
+		if header.e_phentsize as usize != size_of::<ELF64ProgramHeader>() {
+			return Err(ElfError::BadClass);
+		}
+
+		let table_end = (header.e_phnum as u64)
+			.checked_mul(header.e_phentsize as u64)
+			.and_then(|size| size.checked_add(header.e_phoff));
+		if table_end.map_or(true, |end| end > source.len() as u64) {
+			return Err(ElfError::Truncated);
+		}
+
+		let mut segments = Vec::new();
+		for i in 0..header.e_phnum as usize {
+			let phdr: ELF64ProgramHeader = source.read(
+				header.e_phoff as usize + i * size_of::<ELF64ProgramHeader>(),
+			)?;
+			if phdr.p_type == PT_LOAD {
+				segments.push(phdr);
+			}
+		}
+
+		let elf = Self {
+			source,
+			header,
+			segments,
+		};
+		elf.validate_segments()?;
+		Ok(elf)
+	}
+
+	/// Map the segments into `task`'s address space, which has to be the
+	/// current one, set up its stack with `argv` and `envp` and point it at
+	/// the entry.
+	pub fn load(
+		&mut self,
+		task: &mut Task,
+		argv: &[&[u8]],
+		envp: &[&[u8]],
+	) -> Result<(), ElfError> {
+		let pml4 = PageTable::<PML4>::current_mut();
+		let mut page = vec![0u8; PAGE_SIZE];
+
+		// Segments are sorted by address, but may share a page.
+		let mut mapped_end = 0;
+		let mut last_prot = api::PROT_NONE;
+		for phdr in &self.segments {
+			debug!("{phdr:#X?}");
+
+			let start = phdr.vaddr() & !(PAGE_SIZE - 1);
+			let end = (phdr.vaddr() + phdr.p_memsz as usize)
+				.next_multiple_of(PAGE_SIZE);
+			if start < mapped_end {
+				// The shared page needs the permissions of both.
+				last_prot |= phdr.prot();
+				task.memory.protect(pml4, start..mapped_end, last_prot)?;
+			}
+			if mapped_end.max(start) < end {
+				let segment = Area::new(
+					mapped_end.max(start)..end,
+					phdr.prot(),
+					Backing::Anonymous,
+				);
+				task.memory.map(segment)?;
+				mapped_end = end;
+				last_prot = phdr.prot();
+			}
+
+			// Copy the file contents a page at a time.
+			let mut done = 0;
+			while done < phdr.p_filesz as usize {
+				let dst = phdr.vaddr() + done;
+				let len = min(
+					PAGE_SIZE - dst % PAGE_SIZE,
+					phdr.p_filesz as usize - done,
+				);
+				self.source
+					.read_at(phdr.p_offset as usize + done, &mut page[..len])?;
+				task.memory.write(pml4, dst, &page[..len])?;
+				done += len;
+			}
+
+			// The rest of the segment is zero-filled on first touch, but the
+			// page holding the end of the file contents is populated already.
+			let data_end = phdr.vaddr() + phdr.p_filesz as usize;
+			let tail = (phdr.p_memsz - phdr.p_filesz) as usize;
+			let tail =
+				tail.min(data_end.next_multiple_of(PAGE_SIZE) - data_end);
+			task.memory.write(pml4, data_end, &[0; PAGE_SIZE][..tail])?;
+		}
+
+		task.memory.init_brk(mapped_end);
+		task.init_stack(argv, envp, &self.auxv())?;
+		task.register_state.rip = self.header.e_entry;
+		Ok(())
+	}
+
+	fn auxv(&self) -> Vec<(u64, u64)> {
+		let mut auxv = vec![
+			(AT_PHENT, self.header.e_phentsize as u64),
+			(AT_PHNUM, self.header.e_phnum as u64),
+			(AT_PAGESZ, PAGE_SIZE as u64),
+			(AT_ENTRY, self.header.e_entry),
+		];
+
+		// The program headers are only in memory if a segment covers them.
+		let phdr = self.segments.iter().find_map(|phdr| {
+			let offset = self.header.e_phoff.checked_sub(phdr.p_offset)?;
+			(offset < phdr.p_filesz).then_some(phdr.p_vaddr + offset)
+		});
+		if let Some(phdr) = phdr {
+			auxv.push((AT_PHDR, phdr));
 		}
+		auxv
 	}
 
-	task.register_state.rip = header.e_entry;
+	fn validate_segments(&self) -> Result<(), ElfError> {
+		let mut last_end = PAGE_SIZE;
+		for (i, phdr) in self.segments.iter().enumerate() {
+			let file_end = phdr.p_offset.checked_add(phdr.p_filesz);
+			if file_end.map_or(true, |end| end > self.source.len() as u64) {
+				return Err(ElfError::Truncated);
+			}
+
+			let end = phdr.p_vaddr.checked_add(phdr.p_memsz);
+			if phdr.p_filesz > phdr.p_memsz
+				|| phdr.vaddr() < last_end
+				|| end.map_or(true, |end| end > USER_END as u64)
+			{
+				return Err(ElfError::BadSegment(i));
+			}
+			last_end = end.unwrap() as usize;
+		}
+		Ok(())
+	}
 }
@@ -0,0 +1,209 @@
+//! Disk blocks kept in memory between accesses. Writes only dirty the cached
+//! block, it goes to the disk when evicted or on `sync()`.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::{
+	cmp::min,
+	mem::{size_of, MaybeUninit},
+	ops::Range,
+	ptr,
+};
+
+use log::trace;
+
+use crate::{
+	devices::ide,
+	sync::{SpinLock, StaticPtr},
+};
+
+/// Blocks kept before the least recently used one is evicted.
+const CAPACITY: usize = 256;
+
+static CACHE: StaticPtr<SpinLock<BlockCache>> = StaticPtr::new();
+
+#[derive(Debug)]
+struct Buffer {
+	data: Vec<u8>,
+	dirty: bool,
+	/// `BlockCache::clock` at the last access.
+	used: u64,
+}
+
+/// Buffers keyed by device and block number.
+#[derive(Debug)]
+struct BlockCache {
+	buffers: BTreeMap<(u8, u32), Buffer>,
+	clock: u64,
+}
+
+/// A disk accessed through the cache in blocks of `block_size` bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct Disk {
+	device: u8,
+	block_size: usize,
+}
+
+pub fn init() {
+	CACHE.init(SpinLock::new(BlockCache {
+		buffers: BTreeMap::new(),
+		clock: 0,
+	}))
+}
+
+/// Write every dirty block back to its disk.
+pub fn sync() {
+	CACHE.get().lock().sync();
+}
+
+impl BlockCache {
+	/// The buffer of `block` on `disk`, read from the disk on a miss.
+	fn get(&mut self, disk: Disk, block: u32) -> &mut Buffer {
+		self.clock += 1;
+
+		let key = (disk.device, block);
+		if !self.buffers.contains_key(&key) {
+			if self.buffers.len() >= CAPACITY {
+				self.evict();
+			}
+
+			let mut data = vec![0u8; disk.block_size];
+			ide::read(
+				disk.device,
+				disk.sector(block),
+				0,
+				data.as_mut_ptr(),
+				data.len(),
+			);
+			self.buffers.insert(
+				key,
+				Buffer {
+					data,
+					dirty: false,
+					used: 0,
+				},
+			);
+		}
+
+		let buffer = self.buffers.get_mut(&key).unwrap();
+		buffer.used = self.clock;
+		buffer
+	}
+
+	/// Drop the least recently used buffer, writing it back if it's dirty.
+	fn evict(&mut self) {
+		let Some(key) = self
+			.buffers
+			.iter()
+			.min_by_key(|(_, buffer)| buffer.used)
+			.map(|(key, _)| *key)
+		else {
+			return;
+		};
+
+		let buffer = self.buffers.remove(&key).unwrap();
+		if buffer.dirty {
+			write_back(key, &buffer);
+		}
+	}
+
+	fn sync(&mut self) {
+		for (key, buffer) in &mut self.buffers {
+			if buffer.dirty {
+				write_back(*key, buffer);
+				buffer.dirty = false;
+			}
+		}
+	}
+}
+
+fn write_back((device, block): (u8, u32), buffer: &Buffer) {
+	trace!("cache::write_back({device}, {block})");
+	let sector = block * (buffer.data.len() / ide::SECTOR_SIZE) as u32;
+	ide::write(device, sector, 0, buffer.data.as_ptr(), buffer.data.len());
+}
+
+impl Disk {
+	pub fn new(device: u8, block_size: usize) -> Self {
+		assert!(block_size % ide::SECTOR_SIZE == 0);
+		Self { device, block_size }
+	}
+
+	pub fn block_size(&self) -> usize {
+		self.block_size
+	}
+
+	/// Read `len` bytes at `offset` bytes into `block`, running on into the
+	/// blocks after it.
+	pub fn read(&self, block: u32, offset: usize, dst: *mut u8, len: usize) {
+		// `dst` may be user memory that faults its page in through the cache,
+		// so copy out under the lock and into `dst` after.
+		let mut bytes = vec![0u8; len];
+		{
+			let mut cache = CACHE.get().lock();
+			self.split(block, offset, len, |block, range, done| {
+				let buffer = cache.get(*self, block);
+				bytes[done..done + range.len()]
+					.copy_from_slice(&buffer.data[range]);
+			});
+		}
+		unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, len) };
+	}
+
+	/// Write `len` bytes from `src` at `offset` bytes into `block`, running on
+	/// into the blocks after it.
+	pub fn write(&self, block: u32, offset: usize, src: *const u8, len: usize) {
+		let mut bytes = vec![0u8; len];
+		unsafe { ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), len) };
+
+		let mut cache = CACHE.get().lock();
+		self.split(block, offset, len, |block, range, done| {
+			let buffer = cache.get(*self, block);
+			let chunk = range.len();
+			buffer.data[range].copy_from_slice(&bytes[done..done + chunk]);
+			buffer.dirty = true;
+		});
+	}
+
+	/// Read the `T` at `offset` bytes into `block`.
+	pub fn read_type<T>(&self, block: u32, offset: usize) -> T {
+		let mut value = MaybeUninit::<T>::uninit();
+		self.read(block, offset, value.as_mut_ptr() as *mut u8, size_of::<T>());
+		unsafe { value.assume_init() }
+	}
+
+	pub fn write_type<T>(&self, block: u32, offset: usize, value: &T) {
+		self.write(
+			block,
+			offset,
+			value as *const T as *const u8,
+			size_of::<T>(),
+		);
+	}
+
+	/// Cut `len` bytes at `offset` into `block` at block boundaries, calling
+	/// `f` with each block, the range in it and the bytes before it.
+	fn split(
+		&self,
+		block: u32,
+		offset: usize,
+		len: usize,
+		mut f: impl FnMut(u32, Range<usize>, usize),
+	) {
+		let mut done = 0;
+		while done < len {
+			let position = offset + done;
+			let start = position % self.block_size;
+			let chunk = min(self.block_size - start, len - done);
+			f(
+				block + (position / self.block_size) as u32,
+				start..start + chunk,
+				done,
+			);
+			done += chunk;
+		}
+	}
+
+	fn sector(&self, block: u32) -> u32 {
+		block * (self.block_size / ide::SECTOR_SIZE) as u32
+	}
+}
@@ -1,99 +1,176 @@
-use alloc::{format, string::String, vec, vec::Vec};
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
 use core::{
+	any::Any,
+	cmp::min,
 	fmt::{Display, Formatter, Write},
-	mem,
+	ptr, slice, str,
 };
 
+use libc::api;
+
 use crate::{
 	devices::{serial::com1, tty::tty0},
-	fs::inode::{Inode, InodeHash},
+	fs::inode::{DirEntry, File, INode, Inode, InodeKey, Stat},
 };
 
-#[derive(Debug, Copy, Clone)]
-pub enum DeviceInode {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Device {
 	Root,
 	Console,
 	Serial,
 }
 
-impl DeviceInode {
-	pub fn lookup(&self, name: &str) -> Option<Inode> {
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInode {
+	/// Id of the `DeviceFileSystem`.
+	pub(super) fs: usize,
+	pub(super) device: Device,
+}
+
+impl Device {
+	fn from_name(name: &str) -> Option<Self> {
 		match name {
-			"tty0" => Some(Inode::Device(DeviceInode::Console)),
-			"com1" => Some(Inode::Device(DeviceInode::Serial)),
+			"tty0" => Some(Device::Console),
+			"com1" => Some(Device::Serial),
 			_ => None,
 		}
 	}
 
-	pub fn readdir(&self) -> Vec<Inode> {
-		match self {
-			DeviceInode::Root => {
-				vec![DeviceInode::Console.into(), DeviceInode::Serial.into()]
-			}
-			DeviceInode::Console => vec![DeviceInode::Console.into()],
-			DeviceInode::Serial => vec![DeviceInode::Serial.into()],
-		}
-	}
-
-	pub fn hash(&self) -> InodeHash {
-		InodeHash::Device(mem::discriminant(self))
+	fn inumber(&self) -> u64 {
+		*self as u64 + 1
 	}
 }
 
 // TODO: This not here.
-impl DeviceInode {
+impl Device {
 	pub fn read_line(&self) -> String {
 		match self {
 			#[cfg(feature = "gfx")]
-			DeviceInode::Console => vdt0().read_line(),
-			DeviceInode::Console => tty0().lock().read_line(),
-			DeviceInode::Serial => todo!(),
+			Device::Console => vdt0().read_line(),
+			Device::Console => tty0().read_line(),
+			Device::Serial => todo!(),
 			_ => unimplemented!(),
 		}
 	}
 }
 
-// TODO: Again, all this probably comes from `stat` when it exists.
-impl DeviceInode {
+impl Device {
 	pub fn name(&self) -> String {
 		match self {
 			Self::Root => String::from("/"),
 			node => format!("{node}").to_ascii_lowercase(),
 		}
 	}
-
-	pub fn size(&self) -> usize {
-		0
-	}
-
-	pub fn is_dir(&self) -> bool {
-		match self {
-			Self::Root => true,
-			_ => false,
-		}
-	}
 }
 
-impl Write for DeviceInode {
+impl Write for Device {
 	fn write_str(&mut self, s: &str) -> core::fmt::Result {
 		match self {
 			#[cfg(feature = "gfx")]
-			DeviceInode::Console => vdt0().write_str(s),
-			DeviceInode::Console => tty0().lock().write_str(s),
-			DeviceInode::Serial => com1().lock().write_str(s),
+			Device::Console => vdt0().write_str(s),
+			Device::Console => tty0().lock().write_str(s),
+			Device::Serial => com1().lock().write_str(s),
 			_ => unimplemented!(),
 		}
 	}
 }
 
-impl From<DeviceInode> for Inode {
-	fn from(value: DeviceInode) -> Self {
-		Self::Device(value)
+impl Display for Device {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		write!(f, "{:?}", self)
 	}
 }
 
-impl Display for DeviceInode {
-	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-		write!(f, "{:?}", self)
+impl DeviceInode {
+	fn node(&self, device: Device) -> Inode {
+		Rc::new(DeviceInode {
+			fs: self.fs,
+			device,
+		})
+	}
+}
+
+/// Devices don't seek, a read gets the next line of input wherever `offset`
+/// is.
+impl File for DeviceInode {
+	fn read_at(&self, _offset: usize, dst: *mut u8, len: usize) -> usize {
+		if self.device == Device::Root {
+			return 0;
+		}
+
+		let line = self.device.read_line();
+		let len = min(len, line.len());
+		unsafe { ptr::copy_nonoverlapping(line.as_ptr(), dst, len) };
+		len
+	}
+
+	fn write_at(&self, _offset: usize, src: *const u8, len: usize) -> usize {
+		if self.device == Device::Root {
+			return 0;
+		}
+
+		let s = unsafe {
+			str::from_utf8_unchecked(slice::from_raw_parts(src, len))
+		};
+		let mut device = self.device;
+		device.write_str(s).expect("failed to write to dev");
+		len
+	}
+}
+
+impl INode for DeviceInode {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: self.fs,
+			inumber: self.device.inumber(),
+		}
+	}
+
+	fn name(&self) -> String {
+		self.device.name()
+	}
+
+	fn parent(&self) -> Option<Inode> {
+		match self.device {
+			Device::Root => None,
+			_ => Some(self.node(Device::Root)),
+		}
+	}
+
+	fn stat(&self) -> Stat {
+		let mode = match self.device {
+			Device::Root => api::S_IFDIR | 0o755,
+			_ => api::S_IFCHR | 0o666,
+		};
+		Stat {
+			mode: mode as u16,
+			links: 1,
+			..Default::default()
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Option<Inode> {
+		match self.device {
+			Device::Root => Some(self.node(Device::from_name(name)?)),
+			_ => None,
+		}
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		match self.device {
+			Device::Root => vec![Device::Console, Device::Serial]
+				.into_iter()
+				.map(|device| DirEntry {
+					inumber: device.inumber(),
+					name: device.name(),
+					kind: api::DT_CHR as u8,
+				})
+				.collect(),
+			_ => Vec::new(),
+		}
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
 	}
 }
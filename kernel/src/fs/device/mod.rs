@@ -1,11 +1,37 @@
-use crate::fs::{device::inode::DeviceInode, inode::Inode};
+use alloc::rc::Rc;
+
+use crate::fs::{
+	device::inode::{Device, DeviceInode},
+	inode::Inode,
+	vfs,
+};
 
 pub mod inode;
 
-pub struct DeviceFileSystem;
+#[derive(Debug)]
+pub struct DeviceFileSystem {
+	id: usize,
+}
 
 impl DeviceFileSystem {
-	pub fn root(&self) -> Inode {
-		Inode::Device(DeviceInode::Root)
+	pub fn new() -> Self {
+		Self { id: vfs::next_id() }
+	}
+}
+
+impl vfs::FileSystem for DeviceFileSystem {
+	fn id(&self) -> usize {
+		self.id
+	}
+
+	fn name(&self) -> &'static str {
+		"devfs"
+	}
+
+	fn root(self: Rc<Self>) -> Inode {
+		Rc::new(DeviceInode {
+			fs: self.id,
+			device: Device::Root,
+		})
 	}
 }
@@ -1,20 +1,42 @@
 use alloc::{
 	boxed::Box,
-	rc::Rc,
+	collections::BTreeMap,
+	rc::{Rc, Weak},
 	string::{String, ToString},
-	sync::Arc,
+	vec,
 	vec::Vec,
 };
-use core::{cmp::min, slice, str};
+use core::{any::Any, cell::RefCell, cmp::min, ptr, str};
 
+use libc::api;
 use log::trace;
 
-use crate::devices::ide;
+use crate::{
+	arch::amd64::clock,
+	devices::ide,
+	fs::{
+		cache::Disk,
+		inode::{self, DirEntry, File, INode, InodeKey, Stat},
+		vfs,
+	},
+};
 
 const ROOT_INODE: u32 = 2;
+const EXT2_MAGIC: u16 = 0xEF53;
+/// The superblock sits 1024 bytes into the disk.
+const SUPERBLOCK_OFFSET: usize = 1024;
+/// Entries of `i_block` pointing straight at data blocks.
+const DIRECT_BLOCKS: usize = 12;
+/// Directory entries record the file type in the high byte of `name_len`.
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const NAME_MAX: usize = 255;
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Superblock {
 	s_inodes_count: u32,
 	s_blocks_count: u32,
@@ -43,10 +65,14 @@ pub struct Superblock {
 	s_def_resgid: u16,
 	s_first_ino: u32,
 	pub s_inode_size: u16,
+	s_block_group_nr: u16,
+	s_feature_compat: u32,
+	s_feature_incompat: u32,
+	s_feature_ro_compat: u32,
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BlockGroupDescriptorTable {
 	bg_block_bitmap: u32,
 	bg_inode_bitmap: u32,
@@ -59,7 +85,7 @@ pub struct BlockGroupDescriptorTable {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct InodeMetadata {
 	pub i_mode: u16,
 	i_uid: u16,
@@ -81,13 +107,15 @@ pub struct InodeMetadata {
 	i_osd2: [u32; 3],
 }
 
-#[derive(Debug, Clone)]
+/// Metadata isn't kept here, every access goes through the block cache so
+/// separate lookups of the same inode see each other's writes.
+#[derive(Debug)]
 pub struct Inode {
-	pub md: InodeMetadata,
-	fs: Arc<FileSystem>,
+	fs: Rc<FileSystem>,
 	name: String,
 	inumber: u32,
 	pub parent: Option<Rc<Inode>>,
+	this: Weak<Inode>,
 }
 
 #[repr(C)]
@@ -106,60 +134,353 @@ pub struct DirectoryEntry {
 
 #[derive(Debug)]
 pub struct FileSystem {
-	device: u8,
+	id: usize,
+	disk: Disk,
 	superblock: Box<Superblock>,
+	/// How many `Inode`s of each inode number are alive. One without links is
+	/// freed with the last, so open files keep their blocks.
+	live: RefCell<BTreeMap<u32, usize>>,
+}
+
+impl vfs::FileSystem for FileSystem {
+	fn id(&self) -> usize {
+		self.id
+	}
+
+	fn name(&self) -> &'static str {
+		"ext2"
+	}
+
+	fn root(self: Rc<Self>) -> inode::Inode {
+		Inode::new(self, String::from("/"), ROOT_INODE, None)
+	}
 }
 
 impl FileSystem {
-	pub fn new(device: u8) -> Self {
-		let superblock = ide::read_type(device, 2);
-		Self { device, superblock }
-	}
-
-	pub fn root(self: &Arc<Self>) -> Rc<Inode> {
-		Rc::new(Inode {
-			md: self.inode(ROOT_INODE),
-			fs: Arc::clone(self),
-			name: String::from("/"),
-			inumber: ROOT_INODE,
-			parent: None,
+	/// None if there's no disk at `device` or it doesn't hold ext2.
+	pub fn new(device: u8) -> Option<Self> {
+		if !ide::is_present(device) {
+			return None;
+		}
+
+		// The block size is in the superblock, so it's read around the cache.
+		let superblock: Box<Superblock> = ide::read_type(device, 2);
+		if superblock.s_magic != EXT2_MAGIC {
+			return None;
+		}
+		let block_size = 1024 << superblock.s_log_block_size as usize;
+		Some(Self {
+			id: vfs::next_id(),
+			disk: Disk::new(device, block_size),
+			superblock,
+			live: RefCell::new(BTreeMap::new()),
 		})
 	}
 
 	pub fn inode(&self, inode: u32) -> InodeMetadata {
+		let (block, offset) = self.inode_location(inode);
+		self.disk.read_type(block, offset)
+	}
+
+	fn write_inode(&self, inode: u32, md: &InodeMetadata) {
+		let (block, offset) = self.inode_location(inode);
+		self.disk.write_type(block, offset, md);
+	}
+
+	/// First block of the inode table holding `inode`, and the offset of its
+	/// slot from there.
+	fn inode_location(&self, inode: u32) -> (u32, usize) {
 		let bgdt = self.block_group_descriptor(self.block_group(inode));
+		let offset = self.superblock.s_inode_size as usize
+			* self.inode_index(inode) as usize;
+		(bgdt.bg_inode_table, offset)
+	}
 
-		let inode_table_sector = self.block_to_sector(bgdt.bg_inode_table);
-		let inode_table_index = self.inode_index(inode);
-		let inode_sector = ((self.superblock.s_inode_size as u32
-			* inode_table_index)
-			/ ide::SECTOR_SIZE as u32)
-			+ inode_table_sector;
-		let inode_sector_offset = (self.superblock.s_inode_size as u32
-			* inode_table_index)
-			% ide::SECTOR_SIZE as u32;
-
-		assert!((self.superblock.s_inode_size as usize) < ide::SECTOR_SIZE);
-
-		*ide::read_offset::<InodeMetadata>(
-			self.device,
-			inode_sector as u32,
-			inode_sector_offset as usize,
+	fn block_group_descriptor(
+		&self,
+		block_group: u32,
+	) -> BlockGroupDescriptorTable {
+		self.disk.read_type(
+			self.descriptor_table(),
+			Self::descriptor_offset(block_group),
 		)
-		.clone()
 	}
 
-	fn block_group_descriptor(
+	fn write_block_group_descriptor(
 		&self,
 		block_group: u32,
-	) -> Box<BlockGroupDescriptorTable> {
-		let descriptor_block =
-			block_group * self.superblock.s_blocks_per_group + 1;
-		ide::read_type(self.device, self.block_to_sector(descriptor_block))
+		bgdt: &BlockGroupDescriptorTable,
+	) {
+		self.disk.write_type(
+			self.descriptor_table(),
+			Self::descriptor_offset(block_group),
+			bgdt,
+		);
+	}
+
+	/// The descriptor table starts in the block after the superblock.
+	fn descriptor_table(&self) -> u32 {
+		self.superblock.s_first_data_block + 1
+	}
+
+	fn descriptor_offset(block_group: u32) -> usize {
+		block_group as usize * size_of::<BlockGroupDescriptorTable>()
+	}
+
+	fn block_groups(&self) -> u32 {
+		self.superblock
+			.s_blocks_count
+			.div_ceil(self.superblock.s_blocks_per_group)
+	}
+
+	/// Apply `blocks` and `inodes` to the free counters of the superblock and
+	/// `block_group`.
+	fn count_free(&self, block_group: u32, blocks: i32, inodes: i32) {
+		let mut bgdt = self.block_group_descriptor(block_group);
+		bgdt.bg_free_blocks_count =
+			bgdt.bg_free_blocks_count.wrapping_add_signed(blocks as i16);
+		bgdt.bg_free_inodes_count =
+			bgdt.bg_free_inodes_count.wrapping_add_signed(inodes as i16);
+		self.write_block_group_descriptor(block_group, &bgdt);
+
+		let mut superblock: Superblock =
+			self.disk.read_type(0, SUPERBLOCK_OFFSET);
+		superblock.s_free_blocks_count =
+			superblock.s_free_blocks_count.wrapping_add_signed(blocks);
+		superblock.s_free_inodes_count =
+			superblock.s_free_inodes_count.wrapping_add_signed(inodes);
+		self.disk.write_type(0, SUPERBLOCK_OFFSET, &superblock);
+	}
+
+	/// Take the first clear bit out of `bits` bits of the bitmap in `block`,
+	/// returns its index.
+	fn take_bit(&self, block: u32, bits: usize) -> Option<usize> {
+		let mut bitmap = vec![0u8; self.block_size()];
+		self.read_block(block, &mut bitmap);
+
+		let bit = (0..bits.min(bitmap.len() * 8))
+			.find(|bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0)?;
+		bitmap[bit / 8] |= 1 << (bit % 8);
+		self.write_block(block, &bitmap);
+		Some(bit)
+	}
+
+	fn clear_bit(&self, block: u32, bit: usize) {
+		let mut byte: u8 = self.disk.read_type(block, bit / 8);
+		byte &= !(1 << (bit % 8));
+		self.disk.write_type(block, bit / 8, &byte);
+	}
+
+	/// Allocate a zeroed block, None if the disk is full.
+	fn alloc_block(&self) -> Option<u32> {
+		let blocks_per_group = self.superblock.s_blocks_per_group;
+		for group in 0..self.block_groups() {
+			let bgdt = self.block_group_descriptor(group);
+			if bgdt.bg_free_blocks_count == 0 {
+				continue;
+			}
+
+			let first =
+				self.superblock.s_first_data_block + group * blocks_per_group;
+			let bits =
+				blocks_per_group.min(self.superblock.s_blocks_count - first);
+			let Some(bit) = self.take_bit(bgdt.bg_block_bitmap, bits as usize)
+			else {
+				continue;
+			};
+			self.count_free(group, -1, 0);
+
+			let block = first + bit as u32;
+			self.write_block(block, &vec![0u8; self.block_size()]);
+			trace!("ext2::alloc_block() = {block}");
+			return Some(block);
+		}
+		None
+	}
+
+	fn free_block(&self, block: u32) {
+		trace!("ext2::free_block({block})");
+		let index = block - self.superblock.s_first_data_block;
+		let group = index / self.superblock.s_blocks_per_group;
+		let bgdt = self.block_group_descriptor(group);
+		self.clear_bit(
+			bgdt.bg_block_bitmap,
+			(index % self.superblock.s_blocks_per_group) as usize,
+		);
+		self.count_free(group, 1, 0);
+	}
+
+	/// Allocate an inode number, None if there are no free inodes.
+	pub fn alloc_inode(&self, is_dir: bool) -> Option<u32> {
+		let inodes_per_group = self.superblock.s_inodes_per_group;
+		for group in 0..self.block_groups() {
+			let bgdt = self.block_group_descriptor(group);
+			if bgdt.bg_free_inodes_count == 0 {
+				continue;
+			}
+
+			let Some(bit) =
+				self.take_bit(bgdt.bg_inode_bitmap, inodes_per_group as usize)
+			else {
+				continue;
+			};
+			self.count_free(group, 0, -1);
+			if is_dir {
+				let mut bgdt = self.block_group_descriptor(group);
+				bgdt.bg_used_dirs_count += 1;
+				self.write_block_group_descriptor(group, &bgdt);
+			}
+
+			let inode = group * inodes_per_group + bit as u32 + 1;
+			trace!("ext2::alloc_inode() = {inode}");
+			return Some(inode);
+		}
+		None
+	}
+
+	pub fn free_inode(&self, inode: u32, is_dir: bool) {
+		trace!("ext2::free_inode({inode})");
+		let group = self.block_group(inode);
+		let bgdt = self.block_group_descriptor(group);
+		self.clear_bit(bgdt.bg_inode_bitmap, self.inode_index(inode) as usize);
+		self.count_free(group, 0, 1);
+		if is_dir {
+			let mut bgdt = self.block_group_descriptor(group);
+			bgdt.bg_used_dirs_count -= 1;
+			self.write_block_group_descriptor(group, &bgdt);
+		}
+	}
+
+	fn read_block(&self, block: u32, buf: &mut [u8]) {
+		self.disk.read(block, 0, buf.as_mut_ptr(), buf.len());
+	}
+
+	fn write_block(&self, block: u32, buf: &[u8]) {
+		self.disk.write(block, 0, buf.as_ptr(), buf.len());
+	}
+
+	/// The block numbers stored in the indirect block `block`.
+	fn read_indirect(&self, block: u32) -> Vec<u32> {
+		let mut entries = vec![0u32; self.block_size() / size_of::<u32>()];
+		self.disk.read(
+			block,
+			0,
+			entries.as_mut_ptr() as *mut u8,
+			self.block_size(),
+		);
+		entries
+	}
+
+	fn write_indirect(&self, block: u32, entries: &[u32]) {
+		self.disk.write(
+			block,
+			0,
+			entries.as_ptr() as *const u8,
+			self.block_size(),
+		);
+	}
+
+	/// Entry `index` of the indirect block `block`.
+	fn indirect_entry(&self, block: u32, index: usize) -> u32 {
+		self.disk.read_type(block, index * size_of::<u32>())
+	}
+
+	fn set_indirect_entry(&self, block: u32, index: usize, entry: u32) {
+		self.disk
+			.write_type(block, index * size_of::<u32>(), &entry);
+	}
+
+	/// Allocate a block for an empty `slot` if `allocate` is set, counting it
+	/// in `allocated`. Returns the block in `slot`, None if the disk is full.
+	fn fill_slot(
+		&self,
+		slot: &mut u32,
+		allocate: bool,
+		allocated: &mut u32,
+	) -> Option<u32> {
+		if *slot == 0 && allocate {
+			*slot = self.alloc_block()?;
+			*allocated += 1;
+		}
+		Some(*slot)
+	}
+
+	/// Free the indirect block `block` and everything below it, `depth`
+	/// levels of indirect blocks deep. Returns how many blocks were freed.
+	fn free_tree(&self, block: u32, depth: usize) -> u32 {
+		let mut freed = 1;
+		for entry in self.read_indirect(block) {
+			if entry == 0 {
+				continue;
+			}
+			freed += match depth {
+				0 => {
+					self.free_block(entry);
+					1
+				}
+				_ => self.free_tree(entry, depth - 1),
+			};
+		}
+		self.free_block(block);
+		freed
+	}
+
+	/// Free all but the first `keep` blocks under the indirect block in
+	/// `slot`, whose entries each cover `stride` blocks. Returns how many
+	/// blocks were freed.
+	fn truncate_tree(
+		&self,
+		slot: &mut u32,
+		depth: usize,
+		keep: usize,
+		stride: usize,
+	) -> u32 {
+		if *slot == 0 {
+			return 0;
+		}
+		if keep == 0 {
+			let freed = self.free_tree(*slot, depth);
+			*slot = 0;
+			return freed;
+		}
+
+		let per_block = self.block_size() / size_of::<u32>();
+		let mut entries = self.read_indirect(*slot);
+		let mut freed = 0;
+		for (i, entry) in entries.iter_mut().enumerate().skip(keep / stride) {
+			let keep = keep.saturating_sub(i * stride);
+			if depth == 0 {
+				if *entry != 0 {
+					self.free_block(*entry);
+					*entry = 0;
+					freed += 1;
+				}
+			} else {
+				freed += self.truncate_tree(
+					entry,
+					depth - 1,
+					keep,
+					stride / per_block,
+				);
+			}
+		}
+		self.write_indirect(*slot, &entries);
+		freed
+	}
+
+	/// The `file_type` to store in directory entries for `mode`.
+	fn file_type(&self, mode: u16) -> u8 {
+		if self.superblock.s_feature_incompat & FEATURE_INCOMPAT_FILETYPE == 0 {
+			0
+		} else if mode & S_IFMT == S_IFDIR {
+			FT_DIR
+		} else {
+			FT_REG_FILE
+		}
 	}
 
 	fn block_size(&self) -> usize {
-		1024 << self.superblock.s_log_block_size as usize
+		self.disk.block_size()
 	}
 
 	fn block_group(&self, inode: u32) -> u32 {
@@ -170,112 +491,751 @@ impl FileSystem {
 		(inode - 1) % self.superblock.s_inodes_per_group
 	}
 
-	fn block_to_sector(&self, block: u32) -> u32 {
-		block * self.block_sector_count() as u32
-	}
-
 	fn block_sector_count(&self) -> usize {
 		self.block_size() / ide::SECTOR_SIZE
 	}
 }
 
 impl Inode {
-	pub fn is_dir(&self) -> bool {
-		self.md.i_mode & 0x4000 != 0
+	fn new(
+		fs: Rc<FileSystem>,
+		name: String,
+		inumber: u32,
+		parent: Option<Rc<Inode>>,
+	) -> Rc<Self> {
+		*fs.live.borrow_mut().entry(inumber).or_default() += 1;
+		Rc::new_cyclic(|this| Inode {
+			fs,
+			name,
+			inumber,
+			parent,
+			this: this.clone(),
+		})
 	}
 
-	pub fn readdir(&self) -> Vec<DirectoryEntry> {
-		assert!(self.is_dir());
+	/// The `Rc` this lives in, to hand out as the parent of children.
+	fn this(&self) -> Rc<Self> {
+		self.this.upgrade().expect("inode outside of its Rc")
+	}
 
-		let mut entries = Vec::new();
+	pub fn metadata(&self) -> InodeMetadata {
+		self.fs.inode(self.inumber)
+	}
 
-		let mut dirs = ide::read_sector_bytes(
-			self.fs.device,
-			self.fs.block_to_sector(self.md.i_block[0]),
-		);
+	/// The entries of this directory, none if it isn't one.
+	fn dirents(&self) -> Vec<DirectoryEntry> {
+		if !self.is_dir() {
+			return Vec::new();
+		}
 
-		let len = dirs.len();
-		let ptr = dirs.as_mut_ptr();
+		let mut entries = Vec::new();
 
-		let mut offset = 0isize;
-		while offset < len as isize {
-			let header = unsafe {
-				&*(ptr.offset(offset) as *const DirectoryEntryHeader)
-			};
+		let data = self.entries();
 
-			if header.inode == 0 {
-				break;
+		// Entries never cross blocks, `rec_len` leads from one to the next.
+		let mut offset = 0;
+		while let Some(header) = read_header(&data, offset) {
+			let name = entry_name(&data, offset, &header);
+
+			// Unused entries have inode 0. Don't add '.' and '..' for root.
+			if let Some(name) = name.filter(|_| header.inode != 0) {
+				if self.inumber != ROOT_INODE || (name != "." && name != "..") {
+					entries.push(DirectoryEntry {
+						header: header.clone(),
+						name: name.to_string(),
+					});
+				}
 			}
 
-			offset += size_of::<DirectoryEntryHeader>() as isize;
+			offset += header.rec_len as usize;
+		}
+
+		entries
+	}
 
-			let name_len = header.name_len;
-			let name = unsafe {
-				str::from_utf8(slice::from_raw_parts(
-					ptr.offset(offset),
-					name_len as usize,
-				))
-				.unwrap()
+	fn write_data(&self, offset: usize, src: *const u8, len: usize) -> usize {
+		let mut md = self.metadata();
+		let block_size = self.fs.block_size();
+
+		let mut done = 0;
+		while done < len {
+			let position = offset + done;
+			let chunk = min(block_size - position % block_size, len - done);
+
+			let Some(block) = self.block(&mut md, position / block_size, true)
+			else {
+				break;
 			};
+			self.fs.disk.write(
+				block,
+				position % block_size,
+				unsafe { src.add(done) },
+				chunk,
+			);
+			done += chunk;
+		}
+
+		md.i_size = md.i_size.max((offset + done) as u32);
+		self.touch(&mut md);
+		done
+	}
+
+	/// Store `md` with the modification and change times set to now.
+	fn touch(&self, md: &mut InodeMetadata) {
+		let now = clock::unix_time() as u32;
+		md.i_mtime = now;
+		md.i_ctime = now;
+		self.fs.write_inode(self.inumber, md);
+	}
+
+	/// Adjust the link count by `delta`, returns the new count.
+	fn update_links(&self, delta: i16) -> u16 {
+		let mut md = self.metadata();
+		md.i_links_count = md.i_links_count.wrapping_add_signed(delta);
+		md.i_ctime = clock::unix_time() as u32;
+		self.fs.write_inode(self.inumber, &md);
+		md.i_links_count
+	}
+
+	/// Remove every link, the inode is freed once it isn't open anymore.
+	fn clear_links(&self) {
+		let mut md = self.metadata();
+		md.i_links_count = 0;
+		md.i_ctime = clock::unix_time() as u32;
+		self.fs.write_inode(self.inumber, &md);
+	}
+
+	/// Free the blocks and the inode once nothing links to it or has it open
+	/// anymore.
+	fn release(&self) {
+		let is_dir = self.is_dir();
+		self.truncate(0);
+
+		let mut md = self.metadata();
+		md.i_links_count = 0;
+		md.i_dtime = clock::unix_time() as u32;
+		self.fs.write_inode(self.inumber, &md);
+		self.fs.free_inode(self.inumber, is_dir);
+	}
+
+	/// Fill a new directory with its '.' and '..' entries.
+	fn init_dir(&self, parent: u32) -> bool {
+		let block_size = self.fs.block_size();
+		let file_type = self.fs.file_type(S_IFDIR);
+		let dot = entry_len(1);
+
+		let mut block = vec![0u8; block_size];
+		put_entry(&mut block, 0, self.inumber, dot, ".", file_type);
+		put_entry(&mut block, dot, parent, block_size - dot, "..", file_type);
+		self.write_data(0, block.as_ptr(), block_size) == block_size
+	}
+
+	fn child(&self, dirent: DirectoryEntry) -> Rc<Inode> {
+		Inode::new(
+			self.fs.clone(),
+			dirent.name,
+			dirent.header.inode,
+			Some(self.this()),
+		)
+	}
 
-			// Don't add '.' and '..' entries for root.
-			if self.inumber != 2 || (name != "." && name != "..") {
-				entries.push(DirectoryEntry {
-					header: header.clone(),
-					name: name.to_string(),
-				});
+	fn entry(&self, name: &str) -> Option<DirectoryEntry> {
+		self.dirents()
+			.into_iter()
+			.find(|dirent| dirent.name == name)
+	}
+
+	/// Raw directory contents, as much as could be read.
+	fn entries(&self) -> Vec<u8> {
+		let mut data = vec![0u8; self.metadata().i_size as usize];
+		let len = self.read_data(0, data.as_mut_ptr(), data.len());
+		data.truncate(len);
+		data
+	}
+
+	/// Offset of the entry `name` in `data`, and of the entry before it if
+	/// it isn't the first in its block.
+	fn find_entry(
+		&self,
+		data: &[u8],
+		name: &str,
+	) -> Option<(usize, Option<usize>)> {
+		let mut offset = 0;
+		let mut previous = None;
+		while let Some(header) = read_header(data, offset) {
+			if offset % self.fs.block_size() == 0 {
+				previous = None;
 			}
+			if header.inode != 0
+				&& entry_name(data, offset, &header) == Some(name)
+			{
+				return Some((offset, previous));
+			}
+			previous = Some(offset);
+			offset += header.rec_len as usize;
+		}
+		None
+	}
+
+	/// Write back the block of `data` holding `offset`.
+	fn store_block(&self, data: &[u8], offset: usize) -> Option<()> {
+		let block_size = self.fs.block_size();
+		let start = offset - offset % block_size;
+		let written =
+			self.write_data(start, data[start..].as_ptr(), block_size);
+		(written == block_size).then_some(())
+	}
+
+	/// Insert an entry for `name`, in the slack after an existing entry or in
+	/// a new block if no record has room.
+	fn add_entry(&self, name: &str, inumber: u32, mode: u16) -> Option<()> {
+		let block_size = self.fs.block_size();
+		let file_type = self.fs.file_type(mode);
+		let needed = entry_len(name.len());
+		let mut data = self.entries();
 
-			offset += name_len as isize;
-			if name_len % 4 != 0 {
-				offset += 4 - (name_len as isize % 4);
+		let mut offset = 0;
+		while let Some(header) = read_header(&data, offset) {
+			let rec_len = header.rec_len as usize;
+			let used = match header.inode {
+				0 => 0,
+				_ => entry_len(header.name_len as usize),
+			};
+			if rec_len >= used + needed {
+				// Shrink the record to its name and take the rest of it.
+				if used != 0 {
+					write_header(
+						&mut data,
+						offset,
+						&DirectoryEntryHeader {
+							rec_len: used as u16,
+							..header
+						},
+					);
+				}
+				put_entry(
+					&mut data,
+					offset + used,
+					inumber,
+					rec_len - used,
+					name,
+					file_type,
+				);
+				return self.store_block(&data, offset);
 			}
+			offset += rec_len;
 		}
 
-		entries
+		let mut block = vec![0u8; block_size];
+		put_entry(&mut block, 0, inumber, block_size, name, file_type);
+		let written = self.write_data(data.len(), block.as_ptr(), block_size);
+		(written == block_size).then_some(())
 	}
 
-	pub fn read(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
-		assert!(!self.is_dir());
-		assert!(offset < 4096, "TODO: read multiple blocks");
+	/// Drop the entry `name`, folding its record into the one before it.
+	fn remove_entry(&self, name: &str) -> Option<()> {
+		let mut data = self.entries();
+		let (offset, previous) = self.find_entry(&data, name)?;
+		let header = read_header(&data, offset)?;
+
+		match previous {
+			Some(previous) => {
+				let before = read_header(&data, previous)?;
+				write_header(
+					&mut data,
+					previous,
+					&DirectoryEntryHeader {
+						rec_len: before.rec_len + header.rec_len,
+						..before
+					},
+				);
+			}
+			// The first record of a block has nothing to merge into, mark
+			// it unused instead.
+			None => write_header(
+				&mut data,
+				offset,
+				&DirectoryEntryHeader { inode: 0, ..header },
+			),
+		}
+		self.store_block(&data, offset)
+	}
 
-		let len = min(self.md.i_size as usize, len);
-		ide::read(
-			self.fs.device,
-			self.fs.block_to_sector(self.md.i_block[0]),
+	/// Point the entry `name` at `inumber`.
+	fn set_entry(&self, name: &str, inumber: u32) -> Option<()> {
+		let mut data = self.entries();
+		let (offset, _) = self.find_entry(&data, name)?;
+		let header = read_header(&data, offset)?;
+		write_header(
+			&mut data,
 			offset,
-			dst,
-			len,
+			&DirectoryEntryHeader {
+				inode: inumber,
+				..header
+			},
 		);
-		len
+		self.store_block(&data, offset)
+	}
+
+	fn read_data(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		let mut md = self.metadata();
+		let len = min(len, (md.i_size as usize).saturating_sub(offset));
+		let block_size = self.fs.block_size();
+
+		let mut done = 0;
+		while done < len {
+			let position = offset + done;
+			let chunk = min(block_size - position % block_size, len - done);
+			let dst = unsafe { dst.add(done) };
+
+			match self.block(&mut md, position / block_size, false) {
+				Some(0) => unsafe { ptr::write_bytes(dst, 0, chunk) },
+				Some(block) => {
+					self.fs.disk.read(block, position % block_size, dst, chunk)
+				}
+				None => break,
+			}
+			done += chunk;
+		}
+		done
+	}
+
+	/// Disk block holding block `index` of the file, 0 for holes. With
+	/// `allocate`, holes and the indirect blocks leading to them are filled
+	/// and counted in `md`, which the caller writes back. None if `index` is
+	/// past what the block map can address or the disk is full.
+	fn block(
+		&self,
+		md: &mut InodeMetadata,
+		index: usize,
+		allocate: bool,
+	) -> Option<u32> {
+		let mut allocated = 0;
+		let block = self.walk(md, index, allocate, &mut allocated);
+		md.i_blocks += allocated * self.fs.block_sector_count() as u32;
+		block
+	}
+
+	fn walk(
+		&self,
+		md: &mut InodeMetadata,
+		index: usize,
+		allocate: bool,
+		allocated: &mut u32,
+	) -> Option<u32> {
+		if index < DIRECT_BLOCKS {
+			return self.fs.fill_slot(
+				&mut md.i_block[index],
+				allocate,
+				allocated,
+			);
+		}
+
+		// The single, double and triple indirect blocks each cover
+		// `per_block` times as many blocks as the level before.
+		let per_block = self.fs.block_size() / size_of::<u32>();
+		let mut index = index - DIRECT_BLOCKS;
+		let mut span = per_block;
+		for (depth, root) in md.i_block[DIRECT_BLOCKS..].iter_mut().enumerate()
+		{
+			if index < span {
+				let block = self.fs.fill_slot(root, allocate, allocated)?;
+				return self.resolve(
+					block,
+					index,
+					span / per_block,
+					depth,
+					allocate,
+					allocated,
+				);
+			}
+			index -= span;
+			span *= per_block;
+		}
+		None
+	}
+
+	/// Walk `depth` levels of indirect blocks down from `block`, each entry
+	/// at the top covering `stride` blocks.
+	fn resolve(
+		&self,
+		mut block: u32,
+		mut index: usize,
+		mut stride: usize,
+		depth: usize,
+		allocate: bool,
+		allocated: &mut u32,
+	) -> Option<u32> {
+		let per_block = self.fs.block_size() / size_of::<u32>();
+		for _ in 0..=depth {
+			if block == 0 {
+				return Some(0);
+			}
+
+			let entry = index / stride;
+			let mut next = self.fs.indirect_entry(block, entry);
+			if next == 0 && allocate {
+				self.fs.fill_slot(&mut next, allocate, allocated)?;
+				self.fs.set_indirect_entry(block, entry, next);
+			}
+
+			block = next;
+			index %= stride;
+			stride /= per_block;
+		}
+		Some(block)
+	}
+}
+
+impl Drop for Inode {
+	fn drop(&mut self) {
+		let mut live = self.fs.live.borrow_mut();
+		let count = live.get_mut(&self.inumber).expect("inode not counted");
+		*count -= 1;
+		if *count > 0 {
+			return;
+		}
+		live.remove(&self.inumber);
+		drop(live);
+
+		if self.metadata().i_links_count == 0 {
+			self.release();
+		}
+	}
+}
+
+impl File for Inode {
+	fn read_at(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		if self.is_dir() {
+			return 0;
+		}
+		self.read_data(offset, dst, len)
+	}
+
+	/// Grows the file as needed, returns how many bytes made it before the
+	/// disk filled up.
+	fn write_at(&self, offset: usize, src: *const u8, len: usize) -> usize {
+		if self.is_dir() {
+			return 0;
+		}
+		self.write_data(offset, src, len)
+	}
+
+	/// Frees the blocks past `len`, growing leaves a hole.
+	fn truncate(&self, len: usize) -> Option<()> {
+		let mut md = self.metadata();
+		let block_size = self.fs.block_size();
+		let per_block = block_size / size_of::<u32>();
+		let keep = len.div_ceil(block_size);
+
+		// What's left of the last block past `len` reads as zeros if the file
+		// grows again.
+		let tail = len % block_size;
+		if tail != 0 && len < md.i_size as usize {
+			let block = self.block(&mut md, len / block_size, false)?;
+			if block != 0 {
+				let zeros = vec![0u8; block_size - tail];
+				self.fs.disk.write(block, tail, zeros.as_ptr(), zeros.len());
+			}
+		}
+
+		let mut freed = 0;
+		for slot in md.i_block[..DIRECT_BLOCKS].iter_mut().skip(keep) {
+			if *slot != 0 {
+				self.fs.free_block(*slot);
+				*slot = 0;
+				freed += 1;
+			}
+		}
+
+		let mut first = DIRECT_BLOCKS;
+		let mut span = per_block;
+		for (depth, slot) in md.i_block[DIRECT_BLOCKS..].iter_mut().enumerate()
+		{
+			freed += self.fs.truncate_tree(
+				slot,
+				depth,
+				keep.saturating_sub(first),
+				span / per_block,
+			);
+			first += span;
+			span *= per_block;
+		}
+
+		md.i_blocks -= freed * self.fs.block_sector_count() as u32;
+		md.i_size = len as u32;
+		self.touch(&mut md);
+		Some(())
 	}
 
-	pub fn lookup(self: &Rc<Self>, name: &str) -> Option<Rc<Inode>> {
+	/// `i_size` is 32 bits.
+	fn max_size(&self) -> usize {
+		u32::MAX as usize
+	}
+}
+
+impl INode for Inode {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: self.fs.id,
+			inumber: self.inumber as u64,
+		}
+	}
+
+	fn name(&self) -> String {
+		self.name.clone()
+	}
+
+	fn parent(&self) -> Option<inode::Inode> {
+		Some(self.parent.clone()?)
+	}
+
+	fn stat(&self) -> Stat {
+		let md = self.metadata();
+		Stat {
+			mode: md.i_mode,
+			size: md.i_size as usize,
+			links: md.i_links_count,
+			uid: md.i_uid as u32,
+			gid: md.i_gid as u32,
+			atime: md.i_atime as u64,
+			mtime: md.i_mtime as u64,
+			ctime: md.i_ctime as u64,
+			blocks: md.i_blocks as u64,
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Option<inode::Inode> {
 		if name == ".." {
-			return self.parent.clone();
+			return self.parent();
 		}
 
 		trace!("lookup({}/{name})", self.name());
 		let dirent = self
-			.readdir()
+			.dirents()
 			.into_iter()
 			.find(|dirent| kdbg!(&dirent.name) == name)?;
-		let inode_md = self.fs.inode(dirent.header.inode);
+		Some(self.child(dirent))
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		self.dirents()
+			.into_iter()
+			.map(|dirent| DirEntry {
+				inumber: dirent.header.inode as u64,
+				name: dirent.name,
+				kind: match dirent.header.file_type {
+					FT_REG_FILE => api::DT_REG as u8,
+					FT_DIR => api::DT_DIR as u8,
+					_ => api::DT_UNKNOWN as u8,
+				},
+			})
+			.collect()
+	}
+
+	/// None if `name` is taken or the disk is full.
+	fn create(&self, name: &str, mode: u16) -> Option<inode::Inode> {
+		if name.len() > NAME_MAX || self.entry(name).is_some() {
+			return None;
+		}
+
+		let is_dir = mode & S_IFMT == S_IFDIR;
+		let inumber = self.fs.alloc_inode(is_dir)?;
+		let now = clock::unix_time() as u32;
+		self.fs.write_inode(
+			inumber,
+			&InodeMetadata {
+				i_mode: mode,
+				i_atime: now,
+				i_ctime: now,
+				i_mtime: now,
+				// Directories are also linked from their own '.'.
+				i_links_count: if is_dir { 2 } else { 1 },
+				..Default::default()
+			},
+		);
 
-		Some(Rc::new(Inode {
-			md: inode_md,
-			fs: self.fs.clone(),
-			name: dirent.name,
-			inumber: dirent.header.inode,
-			parent: Some(self.clone()),
-		}))
+		let inode = Inode::new(
+			self.fs.clone(),
+			name.to_string(),
+			inumber,
+			Some(self.this()),
+		);
+		let initialized = !is_dir || inode.init_dir(self.inumber);
+		if !initialized || self.add_entry(name, inumber, mode).is_none() {
+			inode.clear_links();
+			return None;
+		}
+
+		// The new directory's '..' links back here.
+		if is_dir {
+			self.update_links(1);
+		}
+		Some(inode)
 	}
 
-	pub fn hash(&self) -> u32 {
-		self.inumber
+	/// The file is freed once its last link is gone and it isn't open
+	/// anymore.
+	fn unlink(&self, name: &str) -> Option<()> {
+		let inode = self.child(self.entry(name)?);
+		if inode.is_dir() {
+			return None;
+		}
+
+		self.remove_entry(name)?;
+		inode.update_links(-1);
+		Some(())
 	}
 
-	pub fn name(&self) -> String {
-		self.name.clone()
+	/// The directory can only hold '.' and '..'.
+	fn rmdir(&self, name: &str) -> Option<()> {
+		if name == "." || name == ".." {
+			return None;
+		}
+
+		let dir = self.child(self.entry(name)?);
+		if !dir.is_dir()
+			|| dir
+				.dirents()
+				.iter()
+				.any(|dirent| dirent.name != "." && dirent.name != "..")
+		{
+			return None;
+		}
+
+		self.remove_entry(name)?;
+		dir.clear_links();
+		self.update_links(-1);
+		Some(())
 	}
+
+	/// `target` can't be a directory.
+	fn link(&self, name: &str, target: &inode::Inode) -> Option<()> {
+		let target = target.as_any().downcast_ref::<Inode>()?;
+		if !Rc::ptr_eq(&self.fs, &target.fs)
+			|| target.is_dir()
+			|| name.len() > NAME_MAX
+			|| self.entry(name).is_some()
+		{
+			return None;
+		}
+
+		self.add_entry(name, target.inumber, target.metadata().i_mode)?;
+		target.update_links(1);
+		Some(())
+	}
+
+	/// Replaces what's at `new_name` if it's of the same kind.
+	fn rename(
+		&self,
+		name: &str,
+		to: &inode::Inode,
+		new_name: &str,
+	) -> Option<()> {
+		let to = to.as_any().downcast_ref::<Inode>()?.this();
+		let special = |name: &str| name == "." || name == "..";
+		if !Rc::ptr_eq(&self.fs, &to.fs)
+			|| special(name)
+			|| special(new_name)
+			|| new_name.len() > NAME_MAX
+		{
+			return None;
+		}
+
+		let inode = self.child(self.entry(name)?);
+		let is_dir = inode.is_dir();
+
+		// A directory can't move below itself.
+		let mut ancestor = Some(to.clone());
+		while let Some(dir) = ancestor.filter(|_| is_dir) {
+			if dir.inumber == inode.inumber {
+				return None;
+			}
+			ancestor = dir.parent.clone();
+		}
+
+		if let Some(existing) = to.entry(new_name) {
+			if existing.header.inode == inode.inumber {
+				return Some(());
+			}
+			if to.child(existing).is_dir() != is_dir {
+				return None;
+			}
+			if is_dir {
+				to.rmdir(new_name)?;
+			} else {
+				to.unlink(new_name)?;
+			}
+		}
+
+		to.add_entry(new_name, inode.inumber, inode.metadata().i_mode)?;
+		self.remove_entry(name)?;
+
+		if is_dir && self.inumber != to.inumber {
+			inode.set_entry("..", to.inumber)?;
+			self.update_links(-1);
+			to.update_links(1);
+		}
+		Some(())
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+/// Record length of an entry with a `name_len` byte name.
+fn entry_len(name_len: usize) -> usize {
+	(size_of::<DirectoryEntryHeader>() + name_len).next_multiple_of(4)
+}
+
+/// The entry header at `offset`, None past the end or at a zero `rec_len`.
+fn read_header(data: &[u8], offset: usize) -> Option<DirectoryEntryHeader> {
+	let bytes = data.get(offset..offset + size_of::<DirectoryEntryHeader>())?;
+	let header = unsafe {
+		ptr::read_unaligned(bytes.as_ptr() as *const DirectoryEntryHeader)
+	};
+	(header.rec_len != 0).then_some(header)
+}
+
+fn write_header(data: &mut [u8], offset: usize, header: &DirectoryEntryHeader) {
+	let bytes = &mut data[offset..offset + size_of::<DirectoryEntryHeader>()];
+	unsafe {
+		ptr::write_unaligned(
+			bytes.as_mut_ptr() as *mut DirectoryEntryHeader,
+			header.clone(),
+		)
+	};
+}
+
+fn entry_name<'a>(
+	data: &'a [u8],
+	offset: usize,
+	header: &DirectoryEntryHeader,
+) -> Option<&'a str> {
+	let start = offset + size_of::<DirectoryEntryHeader>();
+	data.get(start..start + header.name_len as usize)
+		.and_then(|name| str::from_utf8(name).ok())
+}
+
+fn put_entry(
+	data: &mut [u8],
+	offset: usize,
+	inode: u32,
+	rec_len: usize,
+	name: &str,
+	file_type: u8,
+) {
+	write_header(
+		data,
+		offset,
+		&DirectoryEntryHeader {
+			inode,
+			rec_len: rec_len as u16,
+			name_len: name.len() as u8,
+			file_type,
+		},
+	);
+	let start = offset + size_of::<DirectoryEntryHeader>();
+	data[start..start + name.len()].copy_from_slice(name.as_bytes());
 }
@@ -0,0 +1,119 @@
+use alloc::{rc::Rc, vec::Vec};
+
+use crate::fs::FileDescriptor;
+
+/// Descriptors a task can have open at once.
+pub const OPEN_MAX: usize = 64;
+
+/// An open file description, shared by the descriptors dup()ed from one
+/// another and by forked children. Dropped with its last descriptor.
+pub type OpenFile = Rc<FileDescriptor>;
+
+#[derive(Debug, Clone)]
+struct Slot {
+	file: OpenFile,
+	/// `FD_CLOEXEC`, closed on a successful exec().
+	cloexec: bool,
+}
+
+/// The file descriptors of a task, indices into its slots. Cloning it for a
+/// forked child shares every open file description.
+#[derive(Debug, Clone, Default)]
+pub struct FdTable {
+	slots: Vec<Option<Slot>>,
+}
+
+impl FdTable {
+	pub fn get(&self, fd: isize) -> Option<&OpenFile> {
+		let slot = self.slots.get(usize::try_from(fd).ok()?)?;
+		slot.as_ref().map(|slot| &slot.file)
+	}
+
+	/// Open descriptors with their open file description, lowest first.
+	pub fn iter(&self) -> impl Iterator<Item = (usize, &OpenFile)> {
+		self.slots
+			.iter()
+			.enumerate()
+			.filter_map(|(fd, slot)| Some((fd, &slot.as_ref()?.file)))
+	}
+
+	/// Put `file` in the lowest free descriptor, None if all `OPEN_MAX` are
+	/// taken.
+	pub fn insert(&mut self, file: OpenFile, cloexec: bool) -> Option<usize> {
+		let fd = match self.slots.iter().position(Option::is_none) {
+			Some(fd) => fd,
+			None if self.slots.len() < OPEN_MAX => {
+				self.slots.push(None);
+				self.slots.len() - 1
+			}
+			None => return None,
+		};
+		self.slots[fd] = Some(Slot { file, cloexec });
+		Some(fd)
+	}
+
+	pub fn close(&mut self, fd: isize) -> Option<()> {
+		let slot = self.slots.get_mut(usize::try_from(fd).ok()?)?;
+		slot.take()?;
+		self.shrink();
+		Some(())
+	}
+
+	/// Another descriptor for the open file description of `fd`, the lowest
+	/// free one. It doesn't inherit close-on-exec.
+	pub fn dup(&mut self, fd: isize) -> Option<usize> {
+		let file = self.get(fd)?.clone();
+		self.insert(file, false)
+	}
+
+	/// Make `new` refer to the open file description of `fd`, closing what
+	/// `new` referred to first.
+	pub fn dup2(&mut self, fd: isize, new: isize) -> Option<usize> {
+		let file = self.get(fd)?.clone();
+		let new = usize::try_from(new).ok().filter(|&new| new < OPEN_MAX)?;
+		if fd as usize == new {
+			return Some(new);
+		}
+
+		if self.slots.len() <= new {
+			self.slots.resize(new + 1, None);
+		}
+		self.slots[new] = Some(Slot {
+			file,
+			cloexec: false,
+		});
+		Some(new)
+	}
+
+	pub fn cloexec(&self, fd: isize) -> Option<bool> {
+		let slot = self.slots.get(usize::try_from(fd).ok()?)?;
+		Some(slot.as_ref()?.cloexec)
+	}
+
+	pub fn set_cloexec(&mut self, fd: isize, cloexec: bool) -> Option<()> {
+		let slot = self.slots.get_mut(usize::try_from(fd).ok()?)?;
+		slot.as_mut()?.cloexec = cloexec;
+		Some(())
+	}
+
+	/// Close the descriptors marked close-on-exec.
+	pub fn close_on_exec(&mut self) {
+		for slot in &mut self.slots {
+			if slot.as_ref().is_some_and(|slot| slot.cloexec) {
+				*slot = None;
+			}
+		}
+		self.shrink();
+	}
+
+	pub fn clear(&mut self) {
+		self.slots.clear();
+	}
+
+	/// Drop the free slots at the end.
+	fn shrink(&mut self) {
+		while let Some(None) = self.slots.last() {
+			self.slots.pop();
+		}
+	}
+}
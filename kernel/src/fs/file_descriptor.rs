@@ -1,77 +1,192 @@
-use core::{cmp::min, ffi::c_char, fmt::Write, ptr, slice, str};
+use alloc::vec::Vec;
+use core::{
+	cell::{Cell, RefCell},
+	mem, ptr,
+};
 
+use libc::api;
 
-use crate::fs::inode::Inode;
+use crate::fs::inode::{DirEntry, Inode};
 
+/// Where `d_name` starts in a record, records are cut short after it.
+const NAME_OFFSET: usize = mem::offset_of!(api::dirent, d_name);
+
+/// An open file description, what a file descriptor refers to. Descriptors
+/// duplicated from one another share it and so its offset.
 #[derive(Debug, Clone)]
 pub struct FileDescriptor {
-	pub(super) offset: usize,
+	/// Byte offset of the next read or write.
+	offset: Cell<usize>,
+	/// Index of the next entry `getdents()` returns.
+	dir_cursor: Cell<usize>,
+	/// The entries `getdents()` reads from, listed on its first call and
+	/// again after a seek.
+	dir_entries: RefCell<Option<Vec<DirEntry>>>,
 	pub inode: Inode,
+	/// The `O_*` flags it was opened with.
+	flags: i32,
 }
 
 impl FileDescriptor {
 	pub fn new(inode: Inode) -> Self {
-		Self { offset: 0, inode }
+		Self::with_flags(inode, 0)
 	}
 
-	pub fn read(&mut self, dst: *mut u8, len: usize) -> usize {
-		assert!(
-			self.offset + len <= 0x1000,
-			"TODO: Read more than one block"
-		);
-		let len = match &self.inode {
-			Inode::Ext2(inode) => inode.read(self.offset, dst, len),
-			Inode::Device(inode) => {
-				let line = inode.read_line();
-				let len = min(len, line.len());
-				unsafe { ptr::copy_nonoverlapping(line.as_ptr(), dst, len) };
-				len
-			}
+	pub fn with_flags(inode: Inode, flags: i32) -> Self {
+		Self {
+			offset: Cell::new(0),
+			dir_cursor: Cell::new(0),
+			dir_entries: RefCell::new(None),
+			inode,
+			flags,
+		}
+	}
+
+	pub fn read(&self, dst: *mut u8, len: usize) -> usize {
+		let len = self.inode.read_at(self.offset.get(), dst, len);
+		self.offset.set(self.offset.get() + len);
+		len
+	}
+
+	/// Read at `offset` without moving the file offset.
+	pub fn pread(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		self.inode.read_at(offset, dst, len)
+	}
+
+	/// Write at `offset` without moving the file offset, `O_APPEND` doesn't
+	/// apply. None if the file would grow past its maximum size.
+	pub fn pwrite(
+		&self,
+		offset: usize,
+		src: *const u8,
+		len: usize,
+	) -> Option<usize> {
+		if !self.fits(offset, len) {
+			return None;
+		}
+		Some(self.inode.write_at(offset, src, len))
+	}
+
+	/// Move the file offset by `offset` from the start with `SEEK_SET`, the
+	/// current offset with `SEEK_CUR` or the end with `SEEK_END`. None for
+	/// pipes, unknown `whence` and offsets before the start or past the
+	/// maximum file size.
+	///
+	/// Directories move their entry cursor instead, by entries, so a `d_off`
+	/// from `getdents()` can be seeked back to.
+	pub fn seek(&self, offset: isize, whence: i32) -> Option<usize> {
+		if !self.is_seekable() {
+			return None;
+		}
+
+		let is_dir = self.inode.is_dir();
+		let cursor = if is_dir {
+			&self.dir_cursor
+		} else {
+			&self.offset
 		};
+		let base = if whence == api::SEEK_SET as i32 {
+			0
+		} else if whence == api::SEEK_CUR as i32 {
+			cursor.get()
+		} else if whence == api::SEEK_END as i32 && is_dir {
+			self.inode.readdir().len()
+		} else if whence == api::SEEK_END as i32 {
+			self.inode.stat().size
+		} else {
+			return None;
+		};
+
+		let offset = base.checked_add_signed(offset)?;
+		if !is_dir && !self.fits(offset, 0) {
+			return None;
+		}
+		if is_dir {
+			self.dir_entries.take();
+		}
+		cursor.set(offset);
+		Some(offset)
+	}
 
-		self.offset += len;
+	/// Whether it was opened for reading.
+	pub fn is_readable(&self) -> bool {
+		self.flags & api::O_ACCMODE as i32 != api::O_WRONLY as i32
+	}
 
-		len
+	/// Whether it was opened for writing.
+	pub fn is_writable(&self) -> bool {
+		self.flags & api::O_ACCMODE as i32 != api::O_RDONLY as i32
+	}
+
+	/// Whether `len` bytes at `offset` stay within the maximum file size.
+	fn fits(&self, offset: usize, len: usize) -> bool {
+		offset
+			.checked_add(len)
+			.is_some_and(|end| end <= self.inode.max_size())
+	}
+
+	/// Whether it has a position to seek, pread() or pwrite() at.
+	pub fn is_seekable(&self) -> bool {
+		self.inode.stat().mode & api::S_IFMT as u16 != api::S_IFIFO as u16
 	}
 
-	pub fn readdir(&mut self, dst: *mut libc::api::dirent) {
-		let dirent = unsafe { &mut *dst };
-
-		match &self.inode {
-			Inode::Ext2(inode) => {
-				inode.readdir().get(self.offset).map(|de| {
-					dirent.d_ino = de.header.inode as u64;
-					for (i, c) in de.name.bytes().enumerate() {
-						dirent.d_name[i] = c as c_char;
-					}
-				});
+	/// Fill `dst` with as many `dirent` records from the entry cursor on as
+	/// fit in `len` bytes, each `d_reclen` long with `d_name` cut short after
+	/// its NUL. Returns the bytes used, 0 at the end of the directory and None
+	/// if the next record doesn't fit.
+	pub fn getdents(&self, dst: *mut u8, len: usize) -> Option<usize> {
+		let mut entries = self.dir_entries.borrow_mut();
+		let entries = entries.get_or_insert_with(|| self.inode.readdir());
+		let mut used = 0;
+
+		let rest = entries.get(self.dir_cursor.get()..).unwrap_or_default();
+		for entry in rest {
+			let name = entry.name.as_bytes();
+			let reclen = (NAME_OFFSET + name.len() + 1).next_multiple_of(8);
+			if used + reclen > len {
+				break;
 			}
-			Inode::Device(inode) => {
-				inode.readdir().get(self.offset).map(|devnode| {
-					dirent.d_ino = 1;
-					for (i, c) in devnode.name().bytes().enumerate() {
-						dirent.d_name[i] = c as c_char;
-					}
-				});
+
+			let next = self.dir_cursor.get() + 1;
+			let dirent = unsafe { dst.add(used) } as *mut api::dirent;
+			unsafe {
+				ptr::addr_of_mut!((*dirent).d_ino)
+					.write_unaligned(entry.inumber);
+				ptr::addr_of_mut!((*dirent).d_off).write_unaligned(next as i64);
+				ptr::addr_of_mut!((*dirent).d_reclen)
+					.write_unaligned(reclen as u16);
+				ptr::addr_of_mut!((*dirent).d_type).write(entry.kind);
+
+				let dst = dst.add(used + NAME_OFFSET);
+				ptr::copy_nonoverlapping(name.as_ptr(), dst, name.len());
+				// The NUL and the padding.
+				ptr::write_bytes(
+					dst.add(name.len()),
+					0,
+					reclen - NAME_OFFSET - name.len(),
+				);
 			}
+			self.dir_cursor.set(next);
+			used += reclen;
 		}
 
-		self.offset += 1;
+		if used == 0 && self.dir_cursor.get() < entries.len() {
+			return None;
+		}
+		Some(used)
 	}
 
-	pub fn write(&mut self, src: *const u8, len: usize) -> usize {
-		match &mut self.inode {
-			Inode::Ext2(_) => todo!(),
-			Inode::Device(inode) => {
-				let s = unsafe {
-					str::from_utf8_unchecked(slice::from_raw_parts(src, len))
-				};
-				inode.write_str(s).expect("failed to write to dev");
-			}
+	/// None if the file would grow past its maximum size.
+	pub fn write(&self, src: *const u8, len: usize) -> Option<usize> {
+		if self.flags & api::O_APPEND as i32 != 0 {
+			self.offset.set(self.inode.stat().size);
+		}
+		if self.is_seekable() && !self.fits(self.offset.get(), len) {
+			return None;
 		}
 
-		self.offset += len;
-
-		len
+		let len = self.inode.write_at(self.offset.get(), src, len);
+		self.offset.set(self.offset.get() + len);
+		Some(len)
 	}
 }
@@ -0,0 +1,331 @@
+//! The initrd, a cpio (newc) or ustar archive served read-only from where the
+//! bootloader left it.
+
+use alloc::{
+	collections::BTreeMap,
+	format,
+	rc::{Rc, Weak},
+	string::{String, ToString},
+	vec,
+	vec::Vec,
+};
+use core::{any::Any, cmp::min, mem, ops::Range, ptr, str};
+
+use libc::api;
+
+use crate::fs::{
+	inode::{self, DirEntry, File, INode, InodeKey, Stat},
+	vfs,
+};
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const USTAR_MAGIC: &[u8] = b"ustar";
+const USTAR_BLOCK: usize = 512;
+
+#[derive(Debug)]
+struct Node {
+	mode: u16,
+	uid: u32,
+	gid: u32,
+	mtime: u64,
+	data: &'static [u8],
+	/// Indices into `FileSystem::nodes`.
+	entries: BTreeMap<String, usize>,
+}
+
+/// Node `n` is inode `n + 1`, the root is the first.
+#[derive(Debug)]
+pub struct FileSystem {
+	id: usize,
+	nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+pub struct Inode {
+	fs: Rc<FileSystem>,
+	name: String,
+	index: usize,
+	parent: Option<Rc<Inode>>,
+	this: Weak<Inode>,
+}
+
+impl FileSystem {
+	/// None if `archive` is neither cpio nor ustar, or is cut short.
+	pub fn new(archive: &'static [u8]) -> Option<Self> {
+		let mut fs = Self {
+			id: vfs::next_id(),
+			nodes: vec![Node::dir()],
+		};
+
+		if archive.starts_with(CPIO_MAGIC) {
+			fs.parse_cpio(archive)?;
+		} else if archive.get(257..262) == Some(USTAR_MAGIC) {
+			fs.parse_ustar(archive)?;
+		} else {
+			return None;
+		}
+		Some(fs)
+	}
+
+	fn parse_cpio(&mut self, archive: &'static [u8]) -> Option<()> {
+		let mut offset = 0;
+		loop {
+			let header = archive.get(offset..offset + CPIO_HEADER_SIZE)?;
+			if !header.starts_with(CPIO_MAGIC) {
+				return None;
+			}
+
+			// Fields are 8 hex digits each, after the magic.
+			let field = |index: usize| {
+				let start = CPIO_MAGIC.len() + index * 8;
+				let digits = str::from_utf8(&header[start..start + 8]).ok()?;
+				usize::from_str_radix(digits, 16).ok()
+			};
+			let size = field(6)?;
+			let name_size = field(11)?;
+
+			// The name ends in a NUL, it and the data are padded to 4 bytes.
+			let name_start = offset + CPIO_HEADER_SIZE;
+			let name = archive
+				.get(name_start..name_start + name_size.checked_sub(1)?)?;
+			let name = str::from_utf8(name).ok()?;
+			let data_start = (name_start + name_size).next_multiple_of(4);
+			let data = archive.get(data_start..data_start + size)?;
+			offset = (data_start + size).next_multiple_of(4);
+
+			if name == CPIO_TRAILER {
+				return Some(());
+			}
+			let node = Node {
+				mode: field(1)? as u16,
+				uid: field(2)? as u32,
+				gid: field(3)? as u32,
+				mtime: field(5)? as u64,
+				data,
+				entries: BTreeMap::new(),
+			};
+			self.add(name, node);
+		}
+	}
+
+	fn parse_ustar(&mut self, archive: &'static [u8]) -> Option<()> {
+		let mut offset = 0;
+		loop {
+			let header = archive.get(offset..offset + USTAR_BLOCK)?;
+			// Two zeroed blocks end the archive, one is enough to stop.
+			if header.iter().all(|&byte| byte == 0) {
+				return Some(());
+			}
+			if header.get(257..262) != Some(USTAR_MAGIC) {
+				return None;
+			}
+
+			let text = |range: Range<usize>| {
+				let field = &header[range];
+				let end = field.iter().position(|&byte| byte == 0);
+				str::from_utf8(&field[..end.unwrap_or(field.len())]).ok()
+			};
+			let octal =
+				|range| usize::from_str_radix(text(range)?.trim(), 8).ok();
+
+			let mode = octal(100..108)? as u16 & 0o7777;
+			let size = octal(124..136)?;
+			let name = text(0..100)?;
+			let prefix = text(345..500)?;
+
+			let data_start = offset + USTAR_BLOCK;
+			let data = archive.get(data_start..data_start + size)?;
+			offset = (data_start + size).next_multiple_of(USTAR_BLOCK);
+
+			let mode = match header[156] {
+				b'0' | 0 => api::S_IFREG as u16 | mode,
+				b'5' => api::S_IFDIR as u16 | mode,
+				// Links and devices aren't supported.
+				_ => continue,
+			};
+			let path = match prefix {
+				"" => name.to_string(),
+				prefix => format!("{prefix}/{name}"),
+			};
+			let node = Node {
+				mode,
+				uid: octal(108..116)? as u32,
+				gid: octal(116..124)? as u32,
+				mtime: octal(136..148)? as u64,
+				data,
+				entries: BTreeMap::new(),
+			};
+			self.add(&path, node);
+		}
+	}
+
+	/// Put `node` in the tree at `path`, making the directories leading to
+	/// it as needed. A directory listed after its entries keeps them.
+	fn add(&mut self, path: &str, mut node: Node) {
+		let mut segments = path
+			.split('/')
+			.filter(|segment| !segment.is_empty() && *segment != ".")
+			.peekable();
+
+		let mut dir = 0;
+		while let Some(segment) = segments.next() {
+			let last = segments.peek().is_none();
+			dir = match self.nodes[dir].entries.get(segment) {
+				Some(&index) => {
+					if last {
+						node.entries =
+							mem::take(&mut self.nodes[index].entries);
+						self.nodes[index] =
+							mem::replace(&mut node, Node::dir());
+					}
+					index
+				}
+				None => {
+					let new = match last {
+						true => mem::replace(&mut node, Node::dir()),
+						false => Node::dir(),
+					};
+					self.nodes.push(new);
+					let index = self.nodes.len() - 1;
+					self.nodes[dir].entries.insert(segment.to_string(), index);
+					index
+				}
+			};
+		}
+	}
+}
+
+impl Node {
+	/// A directory not listed in the archive itself.
+	fn dir() -> Self {
+		Self {
+			mode: api::S_IFDIR as u16 | 0o755,
+			uid: 0,
+			gid: 0,
+			mtime: 0,
+			data: &[],
+			entries: BTreeMap::new(),
+		}
+	}
+}
+
+impl vfs::FileSystem for FileSystem {
+	fn id(&self) -> usize {
+		self.id
+	}
+
+	fn name(&self) -> &'static str {
+		"initrd"
+	}
+
+	fn root(self: Rc<Self>) -> inode::Inode {
+		Inode::new(self, String::from("/"), 0, None)
+	}
+}
+
+impl Inode {
+	fn new(
+		fs: Rc<FileSystem>,
+		name: String,
+		index: usize,
+		parent: Option<Rc<Inode>>,
+	) -> Rc<Self> {
+		Rc::new_cyclic(|this| Inode {
+			fs,
+			name,
+			index,
+			parent,
+			this: this.clone(),
+		})
+	}
+
+	fn this(&self) -> Rc<Self> {
+		self.this.upgrade().expect("inode outside of its Rc")
+	}
+
+	fn node(&self) -> &Node {
+		&self.fs.nodes[self.index]
+	}
+}
+
+/// Read-only, writes don't get anywhere.
+impl File for Inode {
+	fn read_at(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		let Some(bytes) = self.node().data.get(offset..) else {
+			return 0;
+		};
+		let len = min(len, bytes.len());
+		unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, len) };
+		len
+	}
+
+	fn write_at(&self, _offset: usize, _src: *const u8, _len: usize) -> usize {
+		0
+	}
+}
+
+impl INode for Inode {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: self.fs.id,
+			inumber: self.index as u64 + 1,
+		}
+	}
+
+	fn name(&self) -> String {
+		self.name.clone()
+	}
+
+	fn parent(&self) -> Option<inode::Inode> {
+		Some(self.parent.clone()?)
+	}
+
+	fn stat(&self) -> Stat {
+		let node = self.node();
+		Stat {
+			mode: node.mode,
+			size: node.data.len(),
+			links: 1,
+			uid: node.uid,
+			gid: node.gid,
+			atime: node.mtime,
+			mtime: node.mtime,
+			ctime: node.mtime,
+			blocks: 0,
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Option<inode::Inode> {
+		match name {
+			"." => Some(self.this()),
+			".." => self.parent(),
+			_ => {
+				let index = *self.node().entries.get(name)?;
+				Some(Inode::new(
+					self.fs.clone(),
+					name.to_string(),
+					index,
+					Some(self.this()),
+				))
+			}
+		}
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		self.node()
+			.entries
+			.iter()
+			.map(|(name, &index)| DirEntry {
+				inumber: index as u64 + 1,
+				name: name.clone(),
+				kind: inode::dirent_type(self.fs.nodes[index].mode),
+			})
+			.collect()
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
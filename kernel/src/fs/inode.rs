@@ -1,85 +1,113 @@
-use alloc::{rc::Rc, string::String};
-use core::mem;
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::{any::Any, fmt::Debug};
 
 use libc::api;
 
-use crate::fs::{device::inode::DeviceInode, ext2};
+/// An inode of any mounted filesystem.
+pub type Inode = Rc<dyn INode>;
 
-#[derive(Debug, Clone)]
-pub enum Inode {
-	Device(DeviceInode),
-	Ext2(Rc<ext2::Inode>),
+/// Names an inode across filesystems: the id of its filesystem and its
+/// number there.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct InodeKey {
+	pub fs: usize,
+	pub inumber: u64,
 }
 
-#[derive(PartialEq, Debug)]
-pub enum InodeHash {
-	Device(mem::Discriminant<DeviceInode>),
-	Ext2(u32),
+/// What a filesystem knows about an inode, the rest is left at 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+	pub mode: u16,
+	pub size: usize,
+	pub links: u16,
+	pub uid: u32,
+	pub gid: u32,
+	/// Seconds since the epoch.
+	pub atime: u64,
+	pub mtime: u64,
+	pub ctime: u64,
+	/// 512 byte units allocated.
+	pub blocks: u64,
 }
 
-pub trait Stat {
-	fn mode(&self) -> u16;
-	fn size(&self) -> usize;
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+	pub inumber: u64,
+	pub name: String,
+	/// The `DT_*` file type, `DT_UNKNOWN` if the filesystem doesn't keep it.
+	pub kind: u8,
 }
 
-impl Inode {
-	pub fn parent(&self) -> Option<Self> {
-		match self {
-			Self::Device(node) => todo!(),
-			Inode::Ext2(inode) => Some(Inode::Ext2(inode.parent.clone()?)),
-		}
-	}
+/// The `DT_*` file type for `mode`, they're its `S_IFMT` bits shifted down.
+pub fn dirent_type(mode: u16) -> u8 {
+	((mode & api::S_IFMT as u16) >> 12) as u8
+}
+
+/// The contents of an inode.
+pub trait File {
+	/// Read up to `len` bytes at `offset` into `dst`, returns how many were
+	/// read.
+	fn read_at(&self, offset: usize, dst: *mut u8, len: usize) -> usize;
 
-	pub fn lookup(&self, name: &str) -> Option<Self> {
-		match self {
-			Self::Device(node) => Some(node.lookup(name)?),
-			Inode::Ext2(inode) => Some(Inode::Ext2(inode.lookup(name)?)),
-		}
+	/// Write `len` bytes from `src` at `offset`, returns how many were
+	/// written.
+	fn write_at(&self, offset: usize, src: *const u8, len: usize) -> usize;
+
+	/// Cut or grow the contents to `len` bytes.
+	fn truncate(&self, _len: usize) -> Option<()> {
+		None
 	}
 
-	pub fn hash(&self) -> InodeHash {
-		match self {
-			Self::Device(node) => node.hash(),
-			Inode::Ext2(inode) => InodeHash::Ext2(inode.hash()),
-		}
+	/// The size the contents can't grow past.
+	fn max_size(&self) -> usize {
+		isize::MAX as usize
 	}
 }
 
-impl Inode {
-	pub fn name(&self) -> String {
-		match self {
-			Inode::Device(inode) => inode.name(),
-			Inode::Ext2(inode) => inode.name(),
-		}
+/// A node in the tree of a filesystem. Operations the filesystem doesn't
+/// support return None.
+pub trait INode: File + Debug {
+	fn key(&self) -> InodeKey;
+
+	fn name(&self) -> String;
+
+	/// The directory this was looked up in, None for the root.
+	fn parent(&self) -> Option<Inode>;
+
+	fn stat(&self) -> Stat;
+
+	fn lookup(&self, name: &str) -> Option<Inode>;
+
+	fn readdir(&self) -> Vec<DirEntry>;
+
+	/// Create a file called `name` in this directory, or a directory if
+	/// `mode` says so.
+	fn create(&self, _name: &str, _mode: u16) -> Option<Inode> {
+		None
 	}
 
-	pub fn is_dir(&self) -> bool {
-		match self {
-			Inode::Device(inode) => inode.is_dir(),
-			Inode::Ext2(inode) => inode.is_dir(),
-		}
+	fn unlink(&self, _name: &str) -> Option<()> {
+		None
 	}
-}
 
-impl Stat for Inode {
-	fn mode(&self) -> u16 {
-		match self {
-			Inode::Device(inode) => {
-				// TODO: Yuck.
-				if inode.is_dir() {
-					api::S_IFDIR as u16
-				} else {
-					0
-				}
-			}
-			Inode::Ext2(inode) => inode.md.i_mode,
-		}
+	fn rmdir(&self, _name: &str) -> Option<()> {
+		None
 	}
 
-	fn size(&self) -> usize {
-		match self {
-			Inode::Device(inode) => 0,
-			Inode::Ext2(inode) => inode.md.i_size as usize,
-		}
+	/// Add `name` as another link to `target`, on the same filesystem.
+	fn link(&self, _name: &str, _target: &Inode) -> Option<()> {
+		None
+	}
+
+	/// Move the entry `name` to `new_name` in `to`, on the same filesystem.
+	fn rename(&self, _name: &str, _to: &Inode, _new_name: &str) -> Option<()> {
+		None
+	}
+
+	/// Lets a filesystem get at its own type behind another `Inode`.
+	fn as_any(&self) -> &dyn Any;
+
+	fn is_dir(&self) -> bool {
+		self.stat().mode & api::S_IFMT as u16 == api::S_IFDIR as u16
 	}
 }
@@ -1,20 +1,36 @@
+pub mod cache;
 pub mod device;
 pub mod ext2;
+pub mod fd_table;
 pub mod file_descriptor;
+pub mod initrd;
 pub mod inode;
+pub mod pipe;
+pub mod procfs;
+pub mod tmpfs;
+pub mod vfs;
+
+use alloc::{
+	format,
+	rc::Rc,
+	string::{String, ToString},
+	vec::Vec,
+};
 
-use alloc::{format, vec::Vec};
-
+pub use fd_table::FdTable;
 pub use file_descriptor::FileDescriptor;
 
 use crate::{
-	fs::inode::{Inode, InodeHash},
+	fs::inode::{Inode, InodeKey},
 	sync::StaticPtr,
 };
 
 #[derive(Debug)]
 pub struct MountPoint {
-	host_inode_hash: Option<InodeHash>,
+	/// Where it's mounted, from the root.
+	path: String,
+	host_inode_key: Option<InodeKey>,
+	guest: Rc<dyn vfs::FileSystem>,
 	guest_root_inode: Inode,
 }
 
@@ -32,21 +48,58 @@ impl FileSystem {
 			.guest_root_inode
 	}
 
-	pub fn mount_root(&mut self, root: Inode) {
+	pub fn mount_root(&mut self, root: Rc<dyn vfs::FileSystem>) {
 		if !self.mounts.is_empty() {
 			panic!("Root filesystem already mounted");
 		}
-		self.mount_inode(None, root);
+		self.mount_inode(String::from("/"), None, root);
 	}
 
-	pub fn mount(&mut self, path: &str, guest_root: Inode) {
+	pub fn mount(&mut self, path: &str, guest: Rc<dyn vfs::FileSystem>) {
 		// TODO: Handle mount points that don't exist.
 		// TODO: Mount with arbitrary relative paths instead of relative to
 		//       `self.root()`.
 		let host_inode = self
 			.find(self.root(), path)
 			.expect(&format!("Mount point doesn't exist: {path}"));
-		self.mount_inode(Some(host_inode), guest_root);
+		self.mount_inode(path.to_string(), Some(host_inode), guest);
+	}
+
+	pub fn mounts(&self) -> &[MountPoint] {
+		&self.mounts
+	}
+
+	/// The absolute path `inode` was reached through.
+	pub fn path(&self, inode: &Inode) -> String {
+		let mut path = String::new();
+		let mut node = inode.clone();
+		loop {
+			match node.parent() {
+				Some(parent) => {
+					path = format!("/{}{path}", node.name());
+					node = parent;
+				}
+				// The root of a mounted filesystem continues at its mount
+				// point.
+				None => {
+					let mount = self.mounts.iter().find(|mount| {
+						mount.guest_root_inode.key() == node.key()
+					});
+					return match mount {
+						Some(mount) if mount.path != "/" => {
+							format!(
+								"{}{path}",
+								mount.path.trim_end_matches('/')
+							)
+						}
+						Some(_) if path.is_empty() => String::from("/"),
+						Some(_) => path,
+						// Not reached through the mounts, like a pipe.
+						None => format!("{}{path}", node.name()),
+					};
+				}
+			}
+		}
 	}
 
 	pub fn find(&self, base: &Inode, path: &str) -> Option<Inode> {
@@ -75,20 +128,46 @@ impl FileSystem {
 			Some(node)
 		}
 	}
+
+	/// The directory holding `path` and the name of its last segment, for
+	/// creating or removing it.
+	pub fn find_parent<'a>(
+		&self,
+		base: &Inode,
+		path: &'a str,
+	) -> Option<(Inode, &'a str)> {
+		let path = path.trim_end_matches('/');
+		let (dir, name) = match path.rsplit_once('/') {
+			Some(("", name)) => ("/", name),
+			Some((dir, name)) => (dir, name),
+			None => (".", path),
+		};
+		if name.is_empty() || name == "." || name == ".." {
+			return None;
+		}
+		Some((self.find(base, dir)?, name))
+	}
 }
 
 impl FileSystem {
-	fn mount_inode(&mut self, host_inode: Option<Inode>, guest_root: Inode) {
+	fn mount_inode(
+		&mut self,
+		path: String,
+		host_inode: Option<Inode>,
+		guest: Rc<dyn vfs::FileSystem>,
+	) {
 		self.mounts.push(MountPoint {
-			host_inode_hash: host_inode.map(|inode| inode.hash()),
-			guest_root_inode: guest_root,
+			path,
+			host_inode_key: host_inode.map(|inode| inode.key()),
+			guest_root_inode: guest.clone().root(),
+			guest,
 		})
 	}
 
 	fn mount_point(&self, node: &Inode) -> Option<Inode> {
 		for mp in &self.mounts {
-			match mp.host_inode_hash {
-				Some(ref hash) if *hash == node.hash() => {
+			match mp.host_inode_key {
+				Some(key) if key == node.key() => {
 					return Some(mp.guest_root_inode.clone())
 				}
 				_ => continue,
@@ -98,6 +177,16 @@ impl FileSystem {
 	}
 }
 
+impl MountPoint {
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn guest(&self) -> &Rc<dyn vfs::FileSystem> {
+		&self.guest
+	}
+}
+
 static FS: StaticPtr<FileSystem> = StaticPtr::new();
 
 pub fn init() {
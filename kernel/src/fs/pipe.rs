@@ -0,0 +1,222 @@
+//! Anonymous pipes, a ring buffer between a read end and a write end.
+
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::{
+	any::Any,
+	cell::{Cell, RefCell},
+	cmp::min,
+	ptr,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use libc::api;
+
+use crate::{
+	fs::inode::{self, DirEntry, File, INode, InodeKey, Stat},
+	proc::CPU,
+	sched,
+};
+
+/// Bytes buffered before writers block.
+const CAPACITY: usize = 0x1000;
+
+/// Pipes aren't on a filesystem, `vfs::next_id()` never hands this out.
+const PIPE_FS: usize = 0;
+
+static NEXT_PIPE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+struct Pipe {
+	number: u64,
+	buffer: RefCell<Vec<u8>>,
+	/// Index of the oldest byte in `buffer`.
+	start: Cell<usize>,
+	len: Cell<usize>,
+	read_open: Cell<bool>,
+	write_open: Cell<bool>,
+	/// Tasks blocked reading or writing.
+	waiting: RefCell<Vec<u64>>,
+}
+
+/// One end of a pipe. The end is closed when the last open file description
+/// holding it is.
+#[derive(Debug)]
+pub struct End {
+	pipe: Rc<Pipe>,
+	write: bool,
+}
+
+/// A new pipe's read and write ends.
+pub fn pipe() -> (inode::Inode, inode::Inode) {
+	let pipe = Rc::new(Pipe {
+		number: NEXT_PIPE.fetch_add(1, Ordering::Relaxed),
+		buffer: RefCell::new(vec![0; CAPACITY]),
+		start: Cell::new(0),
+		len: Cell::new(0),
+		read_open: Cell::new(true),
+		write_open: Cell::new(true),
+		waiting: RefCell::new(Vec::new()),
+	});
+	let read = Rc::new(End {
+		pipe: pipe.clone(),
+		write: false,
+	});
+	(read, Rc::new(End { pipe, write: true }))
+}
+
+/// Whether `inode` is the write end of a pipe without a read end, writes to
+/// it fail with `EPIPE`.
+pub fn is_broken(inode: &inode::Inode) -> bool {
+	inode
+		.as_any()
+		.downcast_ref::<End>()
+		.is_some_and(|end| end.write && !end.pipe.read_open.get())
+}
+
+impl Pipe {
+	/// Take up to `len` bytes out of the buffer.
+	fn pop(&self, dst: *mut u8, len: usize) -> usize {
+		let buffer = self.buffer.borrow();
+		let len = min(len, self.len.get());
+
+		let mut done = 0;
+		while done < len {
+			let start = self.start.get();
+			let chunk = min(len - done, CAPACITY - start);
+			unsafe {
+				ptr::copy_nonoverlapping(
+					buffer[start..].as_ptr(),
+					dst.add(done),
+					chunk,
+				)
+			};
+			self.start.set((start + chunk) % CAPACITY);
+			self.len.set(self.len.get() - chunk);
+			done += chunk;
+		}
+		len
+	}
+
+	/// Add up to `len` bytes to the buffer.
+	fn push(&self, src: *const u8, len: usize) -> usize {
+		let mut buffer = self.buffer.borrow_mut();
+		let len = min(len, CAPACITY - self.len.get());
+
+		let mut done = 0;
+		while done < len {
+			let end = (self.start.get() + self.len.get()) % CAPACITY;
+			let chunk = min(len - done, CAPACITY - end);
+			unsafe {
+				ptr::copy_nonoverlapping(
+					src.add(done),
+					buffer[end..].as_mut_ptr(),
+					chunk,
+				)
+			};
+			self.len.set(self.len.get() + chunk);
+			done += chunk;
+		}
+		len
+	}
+
+	/// Sleep until the other end reads, writes or closes.
+	fn wait(&self) {
+		let pid = CPU::load().current_task().pid;
+		self.waiting.borrow_mut().push(pid);
+		sched::block();
+	}
+
+	fn wake_all(&self) {
+		for pid in self.waiting.take() {
+			sched::wake(pid);
+		}
+	}
+}
+
+impl Drop for End {
+	fn drop(&mut self) {
+		match self.write {
+			true => self.pipe.write_open.set(false),
+			false => self.pipe.read_open.set(false),
+		}
+		self.pipe.wake_all();
+	}
+}
+
+/// Offsets don't apply, reads and writes block until they can make progress.
+impl File for End {
+	/// Returns 0 at the end of the pipe, once it's empty and the write end is
+	/// closed.
+	fn read_at(&self, _offset: usize, dst: *mut u8, len: usize) -> usize {
+		if self.write {
+			return 0;
+		}
+
+		loop {
+			let read = self.pipe.pop(dst, len);
+			if read > 0 || len == 0 || !self.pipe.write_open.get() {
+				self.pipe.wake_all();
+				return read;
+			}
+			self.pipe.wait();
+		}
+	}
+
+	/// Writes everything unless the read end closes first, returns what was
+	/// written up to then.
+	fn write_at(&self, _offset: usize, src: *const u8, len: usize) -> usize {
+		if !self.write {
+			return 0;
+		}
+
+		let mut done = 0;
+		while done < len && self.pipe.read_open.get() {
+			let written = self.pipe.push(unsafe { src.add(done) }, len - done);
+			if written > 0 {
+				done += written;
+				self.pipe.wake_all();
+			} else {
+				self.pipe.wait();
+			}
+		}
+		done
+	}
+}
+
+impl INode for End {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: PIPE_FS,
+			inumber: self.pipe.number * 2 + self.write as u64,
+		}
+	}
+
+	fn name(&self) -> String {
+		format!("pipe:[{}]", self.pipe.number)
+	}
+
+	fn parent(&self) -> Option<inode::Inode> {
+		None
+	}
+
+	fn stat(&self) -> Stat {
+		Stat {
+			mode: api::S_IFIFO as u16 | 0o600,
+			size: self.pipe.len.get(),
+			links: 1,
+			..Default::default()
+		}
+	}
+
+	fn lookup(&self, _name: &str) -> Option<inode::Inode> {
+		None
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		Vec::new()
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
@@ -0,0 +1,362 @@
+//! Kernel state as text files, generated on every read.
+
+use alloc::{
+	format,
+	rc::{Rc, Weak},
+	string::{String, ToString},
+	vec,
+	vec::Vec,
+};
+use core::{any::Any, cmp::min, fmt::Write, ptr};
+
+use libc::api;
+
+use crate::{
+	arch::amd64::{clock, idt},
+	fs::{
+		fs0,
+		inode::{self, DirEntry, File, INode, InodeKey, Stat},
+		vfs,
+	},
+	kalloc,
+	mem::{frame, vma::Backing, PAGE_SIZE},
+	proc::{Task, TaskState, CPU},
+	sched,
+};
+
+#[derive(Debug)]
+pub struct FileSystem {
+	id: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Node {
+	Root,
+	Meminfo,
+	Mounts,
+	Uptime,
+	Interrupts,
+	/// The directory of a task, by pid.
+	Task(u64),
+	Status(u64),
+	/// The path of the working directory.
+	Cwd(u64),
+	Fds(u64),
+	/// The path of an open file, by pid and descriptor.
+	Fd(u64, usize),
+	Maps(u64),
+	/// The arguments, each ending in a NUL.
+	Cmdline(u64),
+}
+
+#[derive(Debug)]
+pub struct Inode {
+	fs: Rc<FileSystem>,
+	node: Node,
+	parent: Option<Rc<Inode>>,
+	this: Weak<Inode>,
+}
+
+const GLOBAL_FILES: [Node; 4] =
+	[Node::Meminfo, Node::Mounts, Node::Uptime, Node::Interrupts];
+
+impl FileSystem {
+	pub fn new() -> Self {
+		Self { id: vfs::next_id() }
+	}
+}
+
+impl vfs::FileSystem for FileSystem {
+	fn id(&self) -> usize {
+		self.id
+	}
+
+	fn name(&self) -> &'static str {
+		"proc"
+	}
+
+	fn root(self: Rc<Self>) -> inode::Inode {
+		Inode::new(self, Node::Root, None)
+	}
+}
+
+impl Node {
+	fn name(&self) -> String {
+		match self {
+			Node::Root => String::from("/"),
+			Node::Meminfo => String::from("meminfo"),
+			Node::Mounts => String::from("mounts"),
+			Node::Uptime => String::from("uptime"),
+			Node::Interrupts => String::from("interrupts"),
+			Node::Task(pid) => pid.to_string(),
+			Node::Status(_) => String::from("status"),
+			Node::Cwd(_) => String::from("cwd"),
+			Node::Fds(_) => String::from("fd"),
+			Node::Fd(_, fd) => fd.to_string(),
+			Node::Maps(_) => String::from("maps"),
+			Node::Cmdline(_) => String::from("cmdline"),
+		}
+	}
+
+	/// Unique in the filesystem, a task's nodes have its pid in the upper half.
+	fn inumber(&self) -> u64 {
+		match *self {
+			Node::Root => 1,
+			Node::Meminfo => 2,
+			Node::Mounts => 3,
+			Node::Uptime => 4,
+			Node::Interrupts => 5,
+			Node::Task(pid) => pid << 32,
+			Node::Status(pid) => (pid << 32) | 1,
+			Node::Cwd(pid) => (pid << 32) | 2,
+			Node::Fds(pid) => (pid << 32) | 3,
+			Node::Maps(pid) => (pid << 32) | 4,
+			Node::Cmdline(pid) => (pid << 32) | 5,
+			Node::Fd(pid, fd) => (pid << 32) | (0x100 + fd as u64),
+		}
+	}
+
+	fn is_dir(&self) -> bool {
+		matches!(self, Node::Root | Node::Task(_) | Node::Fds(_))
+	}
+
+	/// The entries of a directory node, None if it's gone.
+	fn children(&self) -> Option<Vec<Node>> {
+		match *self {
+			Node::Root => {
+				let tasks = sched::pids().into_iter().map(Node::Task);
+				Some(GLOBAL_FILES.into_iter().chain(tasks).collect())
+			}
+			Node::Task(pid) => sched::with_task(pid, |_| {
+				vec![
+					Node::Status(pid),
+					Node::Cwd(pid),
+					Node::Fds(pid),
+					Node::Maps(pid),
+					Node::Cmdline(pid),
+				]
+			}),
+			Node::Fds(pid) => sched::with_task(pid, |task| {
+				task.open_files
+					.iter()
+					.map(|(fd, _)| Node::Fd(pid, fd))
+					.collect()
+			}),
+			_ => None,
+		}
+	}
+
+	/// The contents of a file node, None if its task is gone.
+	fn content(&self) -> Option<Vec<u8>> {
+		let text = match *self {
+			Node::Meminfo => meminfo(),
+			Node::Mounts => mounts(),
+			Node::Uptime => format!("{}\n", clock::uptime_seconds()),
+			Node::Interrupts => interrupts(),
+			Node::Status(pid) => sched::with_task(pid, status)?,
+			Node::Cwd(pid) => {
+				sched::with_task(pid, |task| fs0().path(&task.cwd) + "\n")?
+			}
+			Node::Fd(pid, fd) => sched::with_task(pid, |task| {
+				let file = task.open_files.get(fd as isize)?;
+				Some(fs0().path(&file.inode) + "\n")
+			})??,
+			Node::Maps(pid) => sched::with_task(pid, maps)?,
+			Node::Cmdline(pid) => {
+				return sched::with_task(pid, |task| task.cmdline.clone())
+			}
+			Node::Root | Node::Task(_) | Node::Fds(_) => return None,
+		};
+		Some(text.into_bytes())
+	}
+}
+
+fn meminfo() -> String {
+	let (total, free) = {
+		let falloc = frame::current_mut().lock();
+		(falloc.total_frames(), falloc.free_frames())
+	};
+	let heap = kalloc::stats();
+
+	let kb = |bytes: usize| bytes / 1024;
+	format!(
+		"MemTotal: {} kB\nMemFree: {} kB\nHeapUsed: {} kB\nHeapFrames: {}\n",
+		kb(total * PAGE_SIZE),
+		kb(free * PAGE_SIZE),
+		kb(heap.in_use),
+		heap.frames,
+	)
+}
+
+fn mounts() -> String {
+	let mut text = String::new();
+	for mount in fs0().mounts() {
+		let name = mount.guest().name();
+		writeln!(text, "{name} {} {name}", mount.path()).unwrap();
+	}
+	text
+}
+
+fn interrupts() -> String {
+	let mut text = String::new();
+	for (irq, count) in idt::counts() {
+		writeln!(text, "{irq:>3}: {count}").unwrap();
+	}
+	text
+}
+
+fn status(task: &Task) -> String {
+	let state = match task.state {
+		TaskState::Runnable => "R (running)",
+		TaskState::Blocked => "S (sleeping)",
+		TaskState::Zombie(_) => "Z (zombie)",
+	};
+	let size: usize =
+		task.memory.areas().map(|area| area.end - area.start).sum();
+	format!(
+		"Name: {}\nState: {state}\nPid: {}\nPPid: {}\nVmSize: {} kB\n",
+		task.name(),
+		task.pid,
+		task.parent.unwrap_or(0),
+		size / 1024,
+	)
+}
+
+/// One line per area: range, protection, file offset and what backs it.
+fn maps(task: &Task) -> String {
+	let mut text = String::new();
+	for area in task.memory.areas() {
+		let prot = |bit, c| if area.prot & bit != 0 { c } else { '-' };
+		let (offset, backing) = match &area.backing {
+			Backing::Anonymous => (0, String::new()),
+			Backing::Stack => (0, String::from("[stack]")),
+			Backing::File(file, offset) => (*offset, fs0().path(&file.inode)),
+		};
+		writeln!(
+			text,
+			"{:016x}-{:016x} {}{}{}p {offset:08x} {backing}",
+			area.start,
+			area.end,
+			prot(api::PROT_READ, 'r'),
+			prot(api::PROT_WRITE, 'w'),
+			prot(api::PROT_EXEC, 'x'),
+		)
+		.unwrap();
+	}
+	text
+}
+
+impl Inode {
+	fn new(
+		fs: Rc<FileSystem>,
+		node: Node,
+		parent: Option<Rc<Inode>>,
+	) -> Rc<Self> {
+		Rc::new_cyclic(|this| Inode {
+			fs,
+			node,
+			parent,
+			this: this.clone(),
+		})
+	}
+
+	fn this(&self) -> Rc<Self> {
+		self.this.upgrade().expect("inode outside of its Rc")
+	}
+
+	fn child(&self, node: Node) -> Rc<Inode> {
+		Inode::new(self.fs.clone(), node, Some(self.this()))
+	}
+}
+
+/// Read-only, writes don't get anywhere.
+impl File for Inode {
+	fn read_at(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		let Some(content) = self.node.content() else {
+			return 0;
+		};
+
+		let Some(bytes) = content.get(offset..) else {
+			return 0;
+		};
+		let len = min(len, bytes.len());
+		unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, len) };
+		len
+	}
+
+	fn write_at(&self, _offset: usize, _src: *const u8, _len: usize) -> usize {
+		0
+	}
+}
+
+impl INode for Inode {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: self.fs.id,
+			inumber: self.node.inumber(),
+		}
+	}
+
+	fn name(&self) -> String {
+		self.node.name()
+	}
+
+	fn parent(&self) -> Option<inode::Inode> {
+		Some(self.parent.clone()?)
+	}
+
+	fn stat(&self) -> Stat {
+		if self.node.is_dir() {
+			return Stat {
+				mode: api::S_IFDIR as u16 | 0o555,
+				links: 2,
+				..Default::default()
+			};
+		}
+		Stat {
+			mode: api::S_IFREG as u16 | 0o444,
+			size: self.node.content().map_or(0, |content| content.len()),
+			links: 1,
+			..Default::default()
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Option<inode::Inode> {
+		match name {
+			"." => return Some(self.this()),
+			".." => return self.parent(),
+			"self" if self.node == Node::Root => {
+				let pid = CPU::load().current_task().pid;
+				return Some(self.child(Node::Task(pid)));
+			}
+			_ => {}
+		}
+
+		let node = self
+			.node
+			.children()?
+			.into_iter()
+			.find(|node| node.name() == name)?;
+		Some(self.child(node))
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		self.node
+			.children()
+			.unwrap_or_default()
+			.into_iter()
+			.map(|node| DirEntry {
+				inumber: node.inumber(),
+				name: node.name(),
+				kind: match node.is_dir() {
+					true => api::DT_DIR as u8,
+					false => api::DT_REG as u8,
+				},
+			})
+			.collect()
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
@@ -0,0 +1,403 @@
+//! Files kept in kernel memory, gone on reboot.
+
+use alloc::{
+	collections::BTreeMap,
+	rc::{Rc, Weak},
+	string::{String, ToString},
+	vec::Vec,
+};
+use core::{
+	any::Any,
+	cell::{Cell, RefCell},
+	cmp::min,
+	ptr,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use libc::api;
+
+use crate::{
+	arch::amd64::clock,
+	fs::{
+		inode::{self, DirEntry, File, INode, InodeKey, Stat},
+		vfs,
+	},
+};
+
+const ROOT_INODE: u64 = 1;
+/// Files can't grow past this, they're kept in the kernel heap.
+const MAX_FILE_SIZE: usize = 0x400_0000;
+
+#[derive(Debug)]
+pub struct FileSystem {
+	id: usize,
+	next_inumber: AtomicU64,
+	root: Rc<Node>,
+}
+
+#[derive(Debug)]
+enum Content {
+	File(Vec<u8>),
+	Dir(BTreeMap<String, Rc<Node>>),
+}
+
+/// A file or directory, shared by the inodes of all its links.
+#[derive(Debug)]
+struct Node {
+	inumber: u64,
+	mode: u16,
+	links: Cell<u16>,
+	content: RefCell<Content>,
+	/// Unix time of the last change to the contents.
+	mtime: Cell<u64>,
+	/// Unix time of the creation, tmpfs has no other metadata changes.
+	ctime: u64,
+}
+
+/// A node as reached through a path, like `ext2::Inode`.
+#[derive(Debug)]
+pub struct Inode {
+	fs: Rc<FileSystem>,
+	name: String,
+	node: Rc<Node>,
+	parent: Option<Rc<Inode>>,
+	this: Weak<Inode>,
+}
+
+impl FileSystem {
+	pub fn new() -> Self {
+		Self {
+			id: vfs::next_id(),
+			next_inumber: AtomicU64::new(ROOT_INODE + 1),
+			root: Node::new(ROOT_INODE, api::S_IFDIR as u16 | 0o1777),
+		}
+	}
+}
+
+impl vfs::FileSystem for FileSystem {
+	fn id(&self) -> usize {
+		self.id
+	}
+
+	fn name(&self) -> &'static str {
+		"tmpfs"
+	}
+
+	fn root(self: Rc<Self>) -> inode::Inode {
+		let root = self.root.clone();
+		Inode::new(self, String::from("/"), root, None)
+	}
+}
+
+impl Node {
+	fn new(inumber: u64, mode: u16) -> Rc<Self> {
+		let is_dir = mode & api::S_IFMT as u16 == api::S_IFDIR as u16;
+		let content = if is_dir {
+			Content::Dir(BTreeMap::new())
+		} else {
+			Content::File(Vec::new())
+		};
+		let now = clock::unix_time();
+		Rc::new(Node {
+			inumber,
+			mode,
+			// Directories are also linked from their own '.'.
+			links: Cell::new(if is_dir { 2 } else { 1 }),
+			content: RefCell::new(content),
+			mtime: Cell::new(now),
+			ctime: now,
+		})
+	}
+
+	fn is_dir(&self) -> bool {
+		matches!(*self.content.borrow(), Content::Dir(_))
+	}
+
+	/// Adjust the link count by `delta`.
+	fn update_links(&self, delta: i16) {
+		self.links.set(self.links.get().wrapping_add_signed(delta));
+	}
+
+	fn is_empty(&self) -> bool {
+		match &*self.content.borrow() {
+			Content::Dir(entries) => entries.is_empty(),
+			Content::File(data) => data.is_empty(),
+		}
+	}
+}
+
+impl Inode {
+	fn new(
+		fs: Rc<FileSystem>,
+		name: String,
+		node: Rc<Node>,
+		parent: Option<Rc<Inode>>,
+	) -> Rc<Self> {
+		Rc::new_cyclic(|this| Inode {
+			fs,
+			name,
+			node,
+			parent,
+			this: this.clone(),
+		})
+	}
+
+	fn this(&self) -> Rc<Self> {
+		self.this.upgrade().expect("inode outside of its Rc")
+	}
+
+	/// The node linked as `name` in this directory.
+	fn entry(&self, name: &str) -> Option<Rc<Node>> {
+		match &*self.node.content.borrow() {
+			Content::Dir(entries) => entries.get(name).cloned(),
+			Content::File(_) => None,
+		}
+	}
+
+	fn child(&self, name: &str, node: Rc<Node>) -> Rc<Inode> {
+		Inode::new(self.fs.clone(), name.to_string(), node, Some(self.this()))
+	}
+
+	fn insert(&self, name: &str, node: Rc<Node>) -> Option<()> {
+		match &mut *self.node.content.borrow_mut() {
+			Content::Dir(entries) => {
+				entries.insert(name.to_string(), node);
+				Some(())
+			}
+			Content::File(_) => None,
+		}
+	}
+
+	fn remove(&self, name: &str) -> Option<Rc<Node>> {
+		match &mut *self.node.content.borrow_mut() {
+			Content::Dir(entries) => entries.remove(name),
+			Content::File(_) => None,
+		}
+	}
+}
+
+impl File for Inode {
+	fn read_at(&self, offset: usize, dst: *mut u8, len: usize) -> usize {
+		let Content::File(data) = &*self.node.content.borrow() else {
+			return 0;
+		};
+
+		let Some(bytes) = data.get(offset..) else {
+			return 0;
+		};
+		let len = min(len, bytes.len());
+		unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dst, len) };
+		len
+	}
+
+	/// Writes nothing past `MAX_FILE_SIZE` or when the heap is full.
+	fn write_at(&self, offset: usize, src: *const u8, len: usize) -> usize {
+		let Some(end) =
+			offset.checked_add(len).filter(|&end| end <= MAX_FILE_SIZE)
+		else {
+			return 0;
+		};
+
+		// `src` may be a private mapping of this file, which faults its pages
+		// in by reading the contents, so copy it out before borrowing them.
+		let mut bytes = Vec::new();
+		if bytes.try_reserve_exact(len).is_err() {
+			return 0;
+		}
+		unsafe {
+			ptr::copy_nonoverlapping(src, bytes.as_mut_ptr(), len);
+			bytes.set_len(len);
+		}
+
+		let Content::File(data) = &mut *self.node.content.borrow_mut() else {
+			return 0;
+		};
+		if data.len() < end {
+			if data.try_reserve(end - data.len()).is_err() {
+				return 0;
+			}
+			data.resize(end, 0);
+		}
+		data[offset..end].copy_from_slice(&bytes);
+		self.node.mtime.set(clock::unix_time());
+		len
+	}
+
+	fn truncate(&self, len: usize) -> Option<()> {
+		if len > MAX_FILE_SIZE {
+			return None;
+		}
+
+		let Content::File(data) = &mut *self.node.content.borrow_mut() else {
+			return None;
+		};
+		data.try_reserve(len.saturating_sub(data.len())).ok()?;
+		data.resize(len, 0);
+		self.node.mtime.set(clock::unix_time());
+		Some(())
+	}
+
+	fn max_size(&self) -> usize {
+		MAX_FILE_SIZE
+	}
+}
+
+impl INode for Inode {
+	fn key(&self) -> InodeKey {
+		InodeKey {
+			fs: self.fs.id,
+			inumber: self.node.inumber,
+		}
+	}
+
+	fn name(&self) -> String {
+		self.name.clone()
+	}
+
+	fn parent(&self) -> Option<inode::Inode> {
+		Some(self.parent.clone()?)
+	}
+
+	fn stat(&self) -> Stat {
+		let size = match &*self.node.content.borrow() {
+			Content::File(data) => data.len(),
+			Content::Dir(_) => 0,
+		};
+		Stat {
+			mode: self.node.mode,
+			size,
+			links: self.node.links.get(),
+			atime: self.node.mtime.get(),
+			mtime: self.node.mtime.get(),
+			ctime: self.node.ctime,
+			blocks: size.div_ceil(512) as u64,
+			..Default::default()
+		}
+	}
+
+	fn lookup(&self, name: &str) -> Option<inode::Inode> {
+		match name {
+			"." => Some(self.this()),
+			".." => self.parent(),
+			_ => Some(self.child(name, self.entry(name)?)),
+		}
+	}
+
+	fn readdir(&self) -> Vec<DirEntry> {
+		match &*self.node.content.borrow() {
+			Content::Dir(entries) => entries
+				.iter()
+				.map(|(name, node)| DirEntry {
+					inumber: node.inumber,
+					name: name.clone(),
+					kind: inode::dirent_type(node.mode),
+				})
+				.collect(),
+			Content::File(_) => Vec::new(),
+		}
+	}
+
+	fn create(&self, name: &str, mode: u16) -> Option<inode::Inode> {
+		if !self.node.is_dir() || self.entry(name).is_some() {
+			return None;
+		}
+
+		let inumber = self.fs.next_inumber.fetch_add(1, Ordering::Relaxed);
+		let node = Node::new(inumber, mode);
+		self.insert(name, node.clone())?;
+		// The new directory's '..' links back here.
+		if node.is_dir() {
+			self.node.update_links(1);
+		}
+		Some(self.child(name, node))
+	}
+
+	fn unlink(&self, name: &str) -> Option<()> {
+		if self.entry(name)?.is_dir() {
+			return None;
+		}
+
+		// Open files keep the node alive until they're closed.
+		let node = self.remove(name)?;
+		node.update_links(-1);
+		Some(())
+	}
+
+	fn rmdir(&self, name: &str) -> Option<()> {
+		let dir = self.entry(name)?;
+		if !dir.is_dir() || !dir.is_empty() {
+			return None;
+		}
+
+		self.remove(name)?;
+		dir.links.set(0);
+		self.node.update_links(-1);
+		Some(())
+	}
+
+	fn link(&self, name: &str, target: &inode::Inode) -> Option<()> {
+		let target = target.as_any().downcast_ref::<Inode>()?;
+		if !Rc::ptr_eq(&self.fs, &target.fs)
+			|| target.node.is_dir()
+			|| self.entry(name).is_some()
+		{
+			return None;
+		}
+
+		self.insert(name, target.node.clone())?;
+		target.node.update_links(1);
+		Some(())
+	}
+
+	fn rename(
+		&self,
+		name: &str,
+		to: &inode::Inode,
+		new_name: &str,
+	) -> Option<()> {
+		let to = to.as_any().downcast_ref::<Inode>()?.this();
+		if !Rc::ptr_eq(&self.fs, &to.fs) || !to.node.is_dir() {
+			return None;
+		}
+
+		let node = self.entry(name)?;
+		let is_dir = node.is_dir();
+
+		// A directory can't move below itself.
+		let mut ancestor = Some(to.clone());
+		while let Some(dir) = ancestor.filter(|_| is_dir) {
+			if Rc::ptr_eq(&dir.node, &node) {
+				return None;
+			}
+			ancestor = dir.parent.clone();
+		}
+
+		if let Some(existing) = to.entry(new_name) {
+			if Rc::ptr_eq(&existing, &node) {
+				return Some(());
+			}
+			if existing.is_dir() != is_dir || is_dir && !existing.is_empty() {
+				return None;
+			}
+			if is_dir {
+				existing.links.set(0);
+				to.node.update_links(-1);
+			} else {
+				existing.update_links(-1);
+			}
+		}
+
+		self.remove(name)?;
+		to.insert(new_name, node)?;
+
+		if is_dir && !Rc::ptr_eq(&self.node, &to.node) {
+			self.node.update_links(-1);
+			to.node.update_links(1);
+		}
+		Some(())
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
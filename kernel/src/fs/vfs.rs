@@ -0,0 +1,25 @@
+use alloc::rc::Rc;
+use core::{
+	fmt::Debug,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::fs::inode::Inode;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Something that can be mounted.
+pub trait FileSystem: Debug {
+	/// Tells inodes of different filesystems apart, from `next_id()`.
+	fn id(&self) -> usize;
+
+	/// The type of filesystem, as listed in /proc/mounts.
+	fn name(&self) -> &'static str;
+
+	fn root(self: Rc<Self>) -> Inode;
+}
+
+/// A fresh filesystem id.
+pub fn next_id() -> usize {
+	NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
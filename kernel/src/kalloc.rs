@@ -1,45 +1,153 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::{
+	alloc::{GlobalAlloc, Layout},
+	ptr,
+};
 
 use log::{info, trace};
 
-use crate::sync::SpinLock;
+use crate::{
+	mem::{frame, PhysicalAddress, PAGE_SIZE},
+	sync::SpinLock,
+};
+
+/// Block sizes carved out of frames, larger allocations get whole frames.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct KernelAllocator {
+	/// Boot heap, used for frames until the frame allocator is up.
+	start: usize,
 	placement: usize,
 	max: usize,
+	/// Free blocks of each size class, linked through their first word.
+	free_lists: [usize; SIZE_CLASSES.len()],
+	stats: HeapStats,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HeapStats {
+	/// Bytes allocated and not freed yet, rounded up to block or frame size.
+	pub in_use: usize,
+	/// Frames taken from the frame allocator, boot heap excluded.
+	pub frames: usize,
+	pub allocs: usize,
+	pub frees: usize,
 }
 
 #[global_allocator]
 static KERNEL_ALLOCATOR: SpinLock<KernelAllocator> =
 	SpinLock::new(KernelAllocator {
-		placement: !0,
-		max: !0,
+		start: 0,
+		placement: 0,
+		max: 0,
+		free_lists: [0; SIZE_CLASSES.len()],
+		stats: HeapStats {
+			in_use: 0,
+			frames: 0,
+			allocs: 0,
+			frees: 0,
+		},
 	});
 
 impl SpinLock<KernelAllocator> {
 	pub fn init(&self, placement: usize, max: usize) {
 		let mut guard = self.lock();
 		guard.max = max;
+		guard.start = placement;
 		guard.placement = placement;
 		info!("KernelAllocator(0x{:016X} - 0x{:016X})", placement, max);
 	}
 }
 
+impl KernelAllocator {
+	fn alloc_block(&mut self, class: usize) -> Option<usize> {
+		if self.free_lists[class] == 0 {
+			self.refill(class)?;
+		}
+
+		let block = self.free_lists[class];
+		self.free_lists[class] = unsafe { *(block as *const usize) };
+		self.stats.in_use += SIZE_CLASSES[class];
+		Some(block)
+	}
+
+	fn free_block(&mut self, class: usize, block: usize) {
+		unsafe { *(block as *mut usize) = self.free_lists[class] };
+		self.free_lists[class] = block;
+		self.stats.in_use -= SIZE_CLASSES[class];
+	}
+
+	/// Split a new frame into free blocks of `class`.
+	fn refill(&mut self, class: usize) -> Option<()> {
+		let page = self.frames(1)?;
+		let size = SIZE_CLASSES[class];
+
+		for block in (page..page + PAGE_SIZE).step_by(size).rev() {
+			unsafe { *(block as *mut usize) = self.free_lists[class] };
+			self.free_lists[class] = block;
+		}
+
+		Some(())
+	}
+
+	fn alloc_pages(&mut self, count: usize) -> Option<usize> {
+		let pages = self.frames(count)?;
+		self.stats.in_use += count * PAGE_SIZE;
+		Some(pages)
+	}
+
+	fn free_pages(&mut self, pages: usize, count: usize) {
+		self.stats.in_use -= count * PAGE_SIZE;
+
+		if (self.start..self.max).contains(&pages) {
+			trace!("leak(0x{pages:016X}, {count}) from boot heap");
+			return;
+		}
+
+		frame::current_mut()
+			.lock()
+			.free_contiguous(PhysicalAddress::from(pages as *mut u8), count);
+		self.stats.frames -= count;
+	}
+
+	/// Take `count` contiguous frames, from the boot heap if the frame
+	/// allocator isn't up yet.
+	fn frames(&mut self, count: usize) -> Option<usize> {
+		if let Some(falloc) = frame::try_current_mut() {
+			let frames = falloc.lock().alloc_contiguous(count).ok()?;
+			self.stats.frames += count;
+			return Some(frames.to_virtual_addr());
+		}
+
+		let start = self.placement.next_multiple_of(PAGE_SIZE);
+		let end = start + count * PAGE_SIZE;
+		if end > self.max {
+			return None;
+		}
+		self.placement = end;
+		Some(start)
+	}
+}
+
+/// Index into `SIZE_CLASSES` for `layout`, None if it needs whole frames.
+fn size_class(layout: &Layout) -> Option<usize> {
+	let size = layout.size().max(layout.align());
+	SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
 unsafe impl GlobalAlloc for SpinLock<KernelAllocator> {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		let mut guard = self.lock();
-		let align = layout.align();
-		guard.placement = (guard.placement & !(align - 1)) + align;
-
-		let placement = guard.placement;
-		let next_placement = placement + layout.size();
+		// Frames are only page aligned.
+		if layout.align() > PAGE_SIZE {
+			return ptr::null_mut();
+		}
 
-		let ptr = if next_placement < guard.max {
-			guard.placement = next_placement;
-			placement
-		} else {
-			0
-		};
+		let mut guard = self.lock();
+		let ptr = match size_class(&layout) {
+			Some(class) => guard.alloc_block(class),
+			None => guard.alloc_pages(layout.size().div_ceil(PAGE_SIZE)),
+		}
+		.unwrap_or(0);
+		guard.stats.allocs += 1;
 
 		trace!("alloc(0x{ptr:016X}, {}[{}])", layout.size(), layout.align());
 
@@ -47,13 +155,27 @@ unsafe impl GlobalAlloc for SpinLock<KernelAllocator> {
 	}
 
 	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-		trace!("leak(0x{ptr:016X?}, {})", layout.size());
+		trace!("dealloc(0x{ptr:016X?}, {})", layout.size());
+
+		let mut guard = self.lock();
+		match size_class(&layout) {
+			Some(class) => guard.free_block(class, ptr as usize),
+			None => guard
+				.free_pages(ptr as usize, layout.size().div_ceil(PAGE_SIZE)),
+		}
+		guard.stats.frees += 1;
 	}
 }
 
-pub fn init(placement: usize, size: usize) {
+/// Returns the end of the heap.
+pub fn init(placement: usize, size: usize) -> usize {
 	let aligned = (placement & !(0x200000 - 1)) + 0x200000;
 	KERNEL_ALLOCATOR.init(aligned, aligned + size);
+	aligned + size
+}
+
+pub fn stats() -> HeapStats {
+	KERNEL_ALLOCATOR.lock().stats
 }
 
 pub fn kmalloc(size: usize, align: usize) -> usize {
@@ -22,16 +22,14 @@ mod logger;
 mod mem;
 mod multiboot;
 mod proc;
+mod sched;
 mod sync;
 mod syscall;
 
-use alloc::{sync::Arc, vec::Vec};
-use core::{
-	arch::asm, cmp::min, mem::size_of, panic::PanicInfo, ptr,
-	slice,
-};
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use core::{cmp::min, mem::size_of, ops::Range, panic::PanicInfo, ptr, slice};
 
-use log::{debug, error};
+use log::{debug, error, warn};
 
 use crate::{
 	arch::amd64::{
@@ -39,10 +37,15 @@ use crate::{
 		vmem::{map_physical_memory, PageTable, PML4},
 	},
 	devices::{ide, keyboard, pci::enumerate_pci, serial, tty, vga},
-	fs::{device::DeviceFileSystem, ext2, fs0, inode::Inode},
+	elf::{Elf, ElfSource},
+	fs::{
+		cache, device::DeviceFileSystem, ext2, fs0, initrd, procfs, tmpfs,
+		FileDescriptor,
+	},
 	logger::KernelLogger,
 	mem::{
-		frame, kernel_map, PhysicalAddress, KERNEL_LMA, KERNEL_VMA, PAGE_SIZE,
+		frame, kernel_map, PhysicalAddress, HUGE_PAGE_SIZE, KERNEL_LMA,
+		KERNEL_VMA,
 	},
 	multiboot::{MultibootInfo, MultibootMmapEntry, MultibootModuleEntry},
 	proc::{Task, CPU},
@@ -50,6 +53,9 @@ use crate::{
 
 static LOGGER: KernelLogger = KernelLogger;
 
+/// Physical memory mapped at `KERNEL_VMA`, frames above it can't be used.
+const PHYSICAL_MEMORY_SIZE: usize = 512 * 2 * HUGE_PAGE_SIZE;
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
 	cli();
@@ -73,7 +79,7 @@ pub extern "C" fn kernel_start(
 	debug!("{:#08X?}", multiboot_magic);
 	kdbg!(multiboot_info);
 
-	kalloc::init(_kernel_end as usize, 0x200000);
+	let heap_end = kalloc::init(_kernel_end as usize, 0x200000);
 
 	debug!("kernel end {:016X}", _kernel_end as usize - KERNEL_VMA);
 
@@ -90,11 +96,11 @@ pub extern "C" fn kernel_start(
 	vga::init();
 	tty::init();
 
-	map_physical_memory(512 * 2 * 0x200000);
+	map_physical_memory(PHYSICAL_MEMORY_SIZE);
 
 	sti();
 
-	init_frame_allocator(&memory_map);
+	init_frame_allocator(multiboot_info, &memory_map, heap_end);
 
 	// Does nothing right now because in named cpu mode QEMU isn't populating
 	// device class fields.
@@ -102,27 +108,53 @@ pub extern "C" fn kernel_start(
 
 	ide::init();
 
-	let mods: &[MultibootModuleEntry] = unsafe {
-		slice::from_raw_parts(
-			PhysicalAddress(multiboot_info.mods_addr as usize).to_virtual(),
-			multiboot_info.mods_count as usize,
-		)
-	};
+	let mods = modules(multiboot_info);
 
 	#[cfg(feature = "gfx")]
 	init_gfx(multiboot_info, mods, kernel_page_table);
 
-	// Map initrd to load the program stored there.
+	// Map the initrd, its archive is read in place as a filesystem. It is
+	// mounted on /initrd, or as the root when there is no disk.
 	kernel_map(
 		kernel_page_table,
 		PhysicalAddress(mods[0].start as usize),
-		(mods[0].end as usize - mods[0].start as usize).div_ceil(PAGE_SIZE),
+		(mods[0].end as usize - mods[0].start as usize)
+			.div_ceil(HUGE_PAGE_SIZE),
 	);
+	let archive = unsafe {
+		slice::from_raw_parts(
+			PhysicalAddress(mods[0].start as usize).to_virtual::<u8>(),
+			(mods[0].end - mods[0].start) as usize,
+		)
+	};
+	let initrd = initrd::FileSystem::new(archive)
+		.expect("initrd isn't a cpio or ustar archive");
 
 	fs::init();
-	let rootfs = Arc::new(ext2::FileSystem::new(0));
-	fs0().mount_root(Inode::Ext2(rootfs.root()));
-	fs0().mount("/dev", DeviceFileSystem.root());
+	cache::init();
+	let initrd = Rc::new(initrd);
+	let init_path = match ext2::FileSystem::new(0) {
+		Some(rootfs) => {
+			fs0().mount_root(Rc::new(rootfs));
+			fs0().mount("/initrd", initrd);
+			"/initrd/init"
+		}
+		// Without a disk the initrd is the root, it has the mount points.
+		None => {
+			warn!("No ext2 disk, mounting the initrd as the root");
+			fs0().mount_root(initrd);
+			"/init"
+		}
+	};
+	fs0().mount("/dev", Rc::new(DeviceFileSystem::new()));
+	fs0().mount("/tmp", Rc::new(tmpfs::FileSystem::new()));
+	fs0().mount("/proc", Rc::new(procfs::FileSystem::new()));
+
+	let init = fs0()
+		.find(fs0().root(), init_path)
+		.expect("no init in the initrd");
+
+	sched::init();
 
 	// First user process.
 	let mut task = Task::new("user");
@@ -132,17 +164,13 @@ pub extern "C" fn kernel_start(
 	cpu.store();
 
 	// Load after page table switch in switch_task().
-	elf::load(
-		PhysicalAddress(mods[0].start as usize).to_virtual(),
-		&mut task,
-	);
+	Elf::parse(ElfSource::File(FileDescriptor::new(init)))
+		.and_then(|mut elf| elf.load(&mut task, &[b"init"], &[]))
+		.expect("can't load the first task");
 
 	// SYSRET to user program.
-	let next_rip = task.register_state.rip;
-	core::mem::forget(task);
-	unsafe {
-		asm!("jmp _syscall_ret", in("rcx") next_rip, options(nostack, noreturn))
-	}
+	sched::spawn(Box::new(task));
+	sched::start();
 }
 
 #[cfg(feature = "gfx")]
@@ -154,7 +182,8 @@ fn init_gfx(
 	kernel_map(
 		kernel_page_table,
 		PhysicalAddress(mods[0].start as usize),
-		(mods[1].end as usize - mods[1].start as usize).div_ceil(PAGE_SIZE),
+		(mods[1].end as usize - mods[1].start as usize)
+			.div_ceil(HUGE_PAGE_SIZE),
 	);
 	video::init(
 		PhysicalAddress(multiboot_info.framebuffer_addr as usize),
@@ -203,32 +232,51 @@ fn identity_map_reserved(
 	}
 }
 
-fn init_frame_allocator(memory_map: &Vec<MultibootMmapEntry>) {
-	let largest_range = memory_map
+fn modules(multiboot_info: &MultibootInfo) -> &'static [MultibootModuleEntry] {
+	unsafe {
+		slice::from_raw_parts(
+			PhysicalAddress(multiboot_info.mods_addr as usize).to_virtual(),
+			multiboot_info.mods_count as usize,
+		)
+	}
+}
+
+fn init_frame_allocator(
+	multiboot_info: &MultibootInfo,
+	memory_map: &Vec<MultibootMmapEntry>,
+	heap_end: usize,
+) {
+	let available: Vec<Range<usize>> = memory_map
 		.iter()
 		// Kind 1 = Available.
 		.filter(|region| region.kind == 1)
-		.max_by(|region_a, region_b| {
-			// For alignment.
-			let a_len = region_a.len;
-			let b_len = region_b.len;
-			a_len.cmp(&b_len)
-		})
-		.expect("No available memory regions.");
-	// Alignment.
-	let largest_range_addr = largest_range.addr;
-	assert_eq!(
-		KERNEL_LMA, largest_range.addr as usize,
-		"Unexpected memory region: {}",
-		largest_range_addr
-	);
-	assert!(
-		largest_range.pages() > 512,
-		"Frame allocator region too small."
+		.map(|region| region.addr as usize..(region.addr + region.len) as usize)
+		.collect();
+
+	let info = multiboot_info as *const MultibootInfo as usize - KERNEL_VMA;
+	let mods = multiboot_info.mods_addr as usize;
+	let mods_size =
+		multiboot_info.mods_count as usize * size_of::<MultibootModuleEntry>();
+	let framebuffer = multiboot_info.framebuffer_addr as usize;
+	let framebuffer_size = multiboot_info.framebuffer_pitch as usize
+		* multiboot_info.framebuffer_height as usize;
+
+	let mut reserved = vec![
+		// Real mode memory and BIOS data.
+		0..KERNEL_LMA,
+		// Kernel image and the boot heap right after it.
+		KERNEL_LMA..heap_end - KERNEL_VMA,
+		info..info + size_of::<MultibootInfo>(),
+		mods..mods + mods_size,
+		framebuffer..framebuffer + framebuffer_size,
+	];
+	reserved.extend(
+		modules(multiboot_info)
+			.iter()
+			.map(|module| module.start as usize..module.end as usize),
 	);
 
-	// TODO: Don't hardcode frame allocator region.
-	frame::init(0xA00000, 512 * PAGE_SIZE);
+	frame::init(&available, &reserved, PHYSICAL_MEMORY_SIZE);
 }
 
 fn map_framebuffer(
@@ -241,7 +289,7 @@ fn map_framebuffer(
 		(multiboot_info.framebuffer_bpp as usize / u8::BITS as usize
 			* multiboot_info.framebuffer_height as usize
 			* multiboot_info.framebuffer_width as usize)
-			.div_ceil(PAGE_SIZE),
+			.div_ceil(HUGE_PAGE_SIZE),
 	);
 }
 
@@ -1,4 +1,6 @@
-use log::{debug, trace};
+use core::{mem::size_of, ops::Range, ptr, slice};
+
+use log::{info, trace};
 
 use crate::{
 	mem::{PhysicalAddress, PAGE_SIZE},
@@ -7,43 +9,247 @@ use crate::{
 
 static FRAME_ALLOCATOR: StaticPtr<SpinLock<FrameAllocator>> = StaticPtr::new();
 
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+/// Bitmap frame allocator over all available physical memory.
 #[derive(Debug)]
 pub struct FrameAllocator {
-	placement: usize,
-	max: usize,
+	/// One bit per frame, set if the frame is free.
+	bitmap: &'static mut [u64],
+	/// Number of page table entries referencing each frame, so copy-on-write
+	/// frames can be shared between address spaces.
+	refs: &'static mut [u16],
+	/// No free frames in `bitmap` below this word.
+	next: usize,
+	total: usize,
+	free: usize,
 }
 
-pub fn init(placement: usize, size: usize) {
-	FRAME_ALLOCATOR.init(SpinLock::new(FrameAllocator::new(placement, size)));
+/// Hand out the frames in `available` physical memory except those in
+/// `reserved`. Frames at or above `limit` are ignored.
+pub fn init(
+	available: &[Range<usize>],
+	reserved: &[Range<usize>],
+	limit: usize,
+) {
+	FRAME_ALLOCATOR.init(SpinLock::new(FrameAllocator::new(
+		available, reserved, limit,
+	)));
 }
 
 pub fn current_mut() -> &'static SpinLock<FrameAllocator> {
 	FRAME_ALLOCATOR.get()
 }
 
+/// None until `init()` ran.
+pub fn try_current_mut() -> Option<&'static SpinLock<FrameAllocator>> {
+	FRAME_ALLOCATOR.try_get().map(|falloc| &*falloc)
+}
+
 impl FrameAllocator {
-	pub fn new(placement: usize, size: usize) -> Self {
-		let aligned = placement & !(PAGE_SIZE - 1);
-		kdbg!(Self {
-			placement: aligned,
-			max: aligned + size,
-		})
-	}
-
-	pub fn alloc(&mut self) -> PhysicalAddress {
-		let placement = self.placement;
-		let next_placement = placement + PAGE_SIZE;
-
-		debug!("placement: {placement:016X}, max: {:016X}", self.max);
-		let page = if next_placement < self.max {
-			self.placement = next_placement;
-			PhysicalAddress(self.placement)
-		} else {
-			PhysicalAddress(0)
+	pub fn new(
+		available: &[Range<usize>],
+		reserved: &[Range<usize>],
+		limit: usize,
+	) -> Self {
+		let end = available
+			.iter()
+			.map(|range| range.end.min(limit))
+			.max()
+			.expect("No available memory regions.");
+		let frames = end / PAGE_SIZE;
+
+		// The bitmap and reference counts live in the first available frames
+		// large enough to hold them.
+		let words = frames.div_ceil(u64::BITS as usize);
+		let meta_size = (words * size_of::<u64>() + frames * size_of::<u16>())
+			.next_multiple_of(PAGE_SIZE);
+		let meta = find_free(available, reserved, limit, meta_size)
+			.expect("No room for the frame allocator.");
+
+		let (bitmap, refs) = unsafe {
+			let base = PhysicalAddress(meta.start).to_virtual::<u8>();
+			ptr::write_bytes(base, 0, meta_size);
+			(
+				slice::from_raw_parts_mut(base as *mut u64, words),
+				slice::from_raw_parts_mut(
+					base.add(words * size_of::<u64>()) as *mut u16,
+					frames,
+				),
+			)
+		};
+
+		let mut allocator = Self {
+			bitmap,
+			refs,
+			next: 0,
+			total: 0,
+			free: 0,
 		};
 
-		trace!("falloc({page:016X?}, {:016X})", PAGE_SIZE);
+		for range in available {
+			allocator.mark(page_align(range, limit), true);
+		}
+		for range in reserved.iter().chain([&meta]) {
+			allocator.mark(range.start..range.end.min(end), false);
+		}
+		allocator.total = allocator.free;
+
+		info!(
+			"FrameAllocator({} frames, metadata at {:016X})",
+			allocator.total, meta.start
+		);
+		allocator
+	}
+
+	pub fn alloc(&mut self) -> Result<PhysicalAddress, OutOfMemory> {
+		self.alloc_contiguous(1)
+	}
+
+	/// Allocate `count` physically contiguous frames.
+	pub fn alloc_contiguous(
+		&mut self,
+		count: usize,
+	) -> Result<PhysicalAddress, OutOfMemory> {
+		let mut first_free = None;
+		let mut run = 0;
+
+		for index in self.next * u64::BITS as usize..self.refs.len() {
+			if !self.is_free(index) {
+				run = 0;
+				continue;
+			}
+			first_free.get_or_insert(index);
+
+			run += 1;
+			if run < count {
+				continue;
+			}
+
+			let first = index + 1 - count;
+			for index in first..=index {
+				self.bitmap[index / u64::BITS as usize] &=
+					!(1 << (index % u64::BITS as usize));
+				self.refs[index] = 1;
+			}
+			self.free -= count;
+			self.next = first_free.unwrap() / u64::BITS as usize;
+
+			let frame = PhysicalAddress(first * PAGE_SIZE);
+			trace!("falloc({frame:016X?}, {:016X})", count * PAGE_SIZE);
+			return Ok(frame);
+		}
+
+		Err(OutOfMemory)
+	}
+
+	/// Return a frame, regardless of how many places still map it.
+	pub fn free(&mut self, frame: PhysicalAddress) {
+		trace!("ffree({frame:016X?})");
+		let index = Self::index_of(frame);
+		let word = index / u64::BITS as usize;
+		let bit = 1 << (index % u64::BITS as usize);
+
+		assert_eq!(self.bitmap[word] & bit, 0, "double free of {frame:016X?}");
+		self.bitmap[word] |= bit;
+		self.refs[index] = 0;
+		self.free += 1;
+		self.next = self.next.min(word);
+	}
+
+	pub fn free_contiguous(&mut self, frame: PhysicalAddress, count: usize) {
+		for i in 0..count {
+			self.free(frame.offset(i * PAGE_SIZE));
+		}
+	}
+
+	/// Map an allocated frame into one more place.
+	pub fn share(&mut self, frame: PhysicalAddress) {
+		self.refs[Self::index_of(frame)] += 1;
+	}
+
+	/// Drop one reference to an allocated frame, freeing it with the last one.
+	pub fn release(&mut self, frame: PhysicalAddress) {
+		let refs = &mut self.refs[Self::index_of(frame)];
+		*refs -= 1;
+		if *refs == 0 {
+			self.free(frame);
+		}
+	}
+
+	pub fn refs(&self, frame: PhysicalAddress) -> u16 {
+		self.refs[Self::index_of(frame)]
+	}
+
+	pub fn total_frames(&self) -> usize {
+		self.total
+	}
+
+	pub fn free_frames(&self) -> usize {
+		self.free
+	}
+
+	fn index_of(frame: PhysicalAddress) -> usize {
+		frame.0 / PAGE_SIZE
+	}
+
+	fn is_free(&self, index: usize) -> bool {
+		self.bitmap[index / u64::BITS as usize]
+			& (1 << (index % u64::BITS as usize))
+			!= 0
+	}
+
+	/// Mark every frame in `range` as free or used.
+	fn mark(&mut self, range: Range<usize>, free: bool) {
+		let first = range.start / PAGE_SIZE;
+		let last = range.end.div_ceil(PAGE_SIZE).min(self.refs.len());
+
+		for index in first..last {
+			let word = &mut self.bitmap[index / u64::BITS as usize];
+			let bit = 1 << (index % u64::BITS as usize);
+
+			if free && *word & bit == 0 {
+				*word |= bit;
+				self.free += 1;
+			} else if !free && *word & bit != 0 {
+				*word &= !bit;
+				self.free -= 1;
+			}
+		}
+	}
+}
+
+/// Shrink `range` to whole frames below `limit`.
+fn page_align(range: &Range<usize>, limit: usize) -> Range<usize> {
+	let start = range.start.next_multiple_of(PAGE_SIZE);
+	let end = range.end.min(limit) & !(PAGE_SIZE - 1);
+	start..end.max(start)
+}
+
+/// First `size` bytes of available memory not overlapping anything reserved.
+fn find_free(
+	available: &[Range<usize>],
+	reserved: &[Range<usize>],
+	limit: usize,
+	size: usize,
+) -> Option<Range<usize>> {
+	for range in available {
+		let range = page_align(range, limit);
+		let mut start = range.start;
 
-		page
+		while start + size <= range.end {
+			let candidate = start..start + size;
+			match reserved.iter().find(|reserved| {
+				reserved.start < candidate.end && candidate.start < reserved.end
+			}) {
+				Some(reserved) => {
+					start = reserved.end.next_multiple_of(PAGE_SIZE)
+				}
+				None => return Some(candidate),
+			}
+		}
 	}
+
+	None
 }
@@ -1,11 +1,13 @@
-use crate::arch::amd64::vmem::{PageTable, PML4};
+use crate::arch::amd64::vmem::{PageSize, PageTable, PML4};
 
 pub mod frame;
+pub mod vma;
 
 // TODO: Define once (already defined in linker and boot.asm).
 pub const KERNEL_VMA: usize = 0xFFFFFF8000000000;
 pub const KERNEL_LMA: usize = 0x0000000000100000;
-pub const PAGE_SIZE: usize = 0x200000;
+pub const PAGE_SIZE: usize = 0x1000;
+pub const HUGE_PAGE_SIZE: usize = 0x200000;
 
 #[derive(Debug)]
 pub struct VirtualAddress(pub usize);
@@ -44,7 +46,7 @@ pub fn kernel_map(
 	start: PhysicalAddress,
 	pages: usize,
 ) {
-	let start = start.page_align_floor();
+	let start = PhysicalAddress(start.0 & !(HUGE_PAGE_SIZE - 1));
 
 	// HACK: Don't touch the first meg. TODO: I can't remember why.
 	if start.0 < 0x100000 {
@@ -53,10 +55,12 @@ pub fn kernel_map(
 
 	for i in 0..pages {
 		pml4.map(
-			start.offset(i * PAGE_SIZE).0,
-			start.offset(i * PAGE_SIZE).to_virtual_addr(),
+			start.offset(i * HUGE_PAGE_SIZE).0,
+			start.offset(i * HUGE_PAGE_SIZE).to_virtual_addr(),
+			PageSize::Huge,
 			0x83,
 			true,
-		);
+		)
+		.expect("out of memory mapping the kernel");
 	}
 }
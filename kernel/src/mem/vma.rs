@@ -0,0 +1,455 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{cmp::min, ops::Range, ptr};
+
+use libc::api;
+
+use crate::{
+	arch::amd64::vmem::{Page, PageTable, PML4},
+	fs::FileDescriptor,
+	mem::{frame, frame::OutOfMemory, PAGE_SIZE},
+};
+
+/// User space is the lower half of the address space.
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+/// Where `mmap()` places mappings without a fixed address.
+const MMAP_BASE: usize = 0x0000_1000_0000_0000;
+/// Stacks grow on faults up to this size.
+const STACK_LIMIT: usize = 0x80_0000;
+
+#[derive(Debug)]
+pub enum MapError {
+	OutOfMemory,
+	/// Outside user space or overlapping another area.
+	BadRange,
+}
+
+impl From<OutOfMemory> for MapError {
+	fn from(_: OutOfMemory) -> Self {
+		MapError::OutOfMemory
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Backing {
+	Anonymous,
+	/// Anonymous memory that grows down on faults right below it, leaving at
+	/// least a guard page to the area below.
+	Stack,
+	/// Private copy of a file, from the given offset at the start of the area.
+	File(FileDescriptor, usize),
+}
+
+/// A page aligned range of user memory with the same protection and backing.
+/// Its pages are only populated on first touch.
+#[derive(Debug, Clone)]
+pub struct Area {
+	pub start: usize,
+	pub end: usize,
+	/// `PROT_*` bits.
+	pub prot: i32,
+	pub backing: Backing,
+}
+
+impl Area {
+	pub fn new(range: Range<usize>, prot: i32, backing: Backing) -> Self {
+		assert_eq!(range.start % PAGE_SIZE, 0);
+		assert_eq!(range.end % PAGE_SIZE, 0);
+		Self {
+			start: range.start,
+			end: range.end,
+			prot,
+			backing,
+		}
+	}
+
+	/// Whether the page fault `access` is allowed.
+	fn allows(&self, access: Access) -> bool {
+		let prot = match access {
+			Access::Read => api::PROT_READ,
+			Access::Write => api::PROT_WRITE,
+			Access::Execute => api::PROT_EXEC,
+		};
+		self.prot & prot != 0
+	}
+
+	fn page_flags(&self) -> u64 {
+		// TODO: Enable NX and honour PROT_EXEC.
+		if self.prot == api::PROT_NONE {
+			// Only the kernel can touch it.
+			Page::PRESENT
+		} else if self.prot & api::PROT_WRITE != 0 {
+			Page::PRESENT | Page::USER | Page::READ_WRITE
+		} else {
+			Page::PRESENT | Page::USER
+		}
+	}
+
+	/// Cut this area at `addr`, returning the upper part.
+	fn split_off(&mut self, addr: usize) -> Area {
+		let mut upper = self.clone();
+		upper.start = addr;
+		if let Backing::File(_, offset) = &mut upper.backing {
+			*offset += addr - self.start;
+		}
+		self.end = addr;
+		upper
+	}
+
+	/// Map a zeroed frame at `page` and fill it from the backing file.
+	fn populate(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		page: usize,
+	) -> Result<(), OutOfMemory> {
+		if pml4
+			.entry_mut(page)
+			.is_some_and(|entry| entry.has(Page::PRESENT))
+		{
+			return Ok(());
+		}
+
+		pml4.map_zeroed(page, self.page_flags())?;
+		self.fill(pml4, page);
+		Ok(())
+	}
+
+	fn fill(&mut self, pml4: &mut PageTable<PML4>, page: usize) {
+		let Backing::File(file, offset) = &mut self.backing else {
+			return;
+		};
+
+		let offset = *offset + page - self.start;
+		let len = min(PAGE_SIZE, file.inode.stat().size.saturating_sub(offset));
+		if len == 0 {
+			return;
+		}
+
+		// Through the physical mapping, the page might be read-only.
+		let frame = pml4.entry_mut(page).unwrap().address();
+		file.pread(offset, frame.to_virtual(), len);
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+	Read,
+	Write,
+	Execute,
+}
+
+/// The areas of a task's user address space.
+#[derive(Debug, Clone, Default)]
+pub struct AddressSpace {
+	/// Areas by start address, never overlapping.
+	areas: BTreeMap<usize, Area>,
+	/// The heap runs from `brk_start` up to `brk`.
+	brk_start: usize,
+	brk: usize,
+}
+
+impl AddressSpace {
+	pub fn areas(&self) -> impl Iterator<Item = &Area> {
+		self.areas.values()
+	}
+
+	/// The area `addr` falls into.
+	pub fn find(&self, addr: usize) -> Option<&Area> {
+		self.areas
+			.range(..=addr)
+			.next_back()
+			.map(|(_, area)| area)
+			.filter(|area| addr < area.end)
+	}
+
+	/// Lowest free range of `len` bytes for `mmap()`.
+	pub fn find_free(&self, len: usize) -> Option<usize> {
+		let mut start = MMAP_BASE;
+		for area in self.areas.values().filter(|area| area.end > MMAP_BASE) {
+			if start + len <= area.start {
+				break;
+			}
+			start = start.max(area.end);
+		}
+
+		(start + len <= USER_END).then_some(start)
+	}
+
+	/// Add `area`, its pages are mapped on first touch.
+	pub fn map(&mut self, area: Area) -> Result<(), MapError> {
+		if area.start < PAGE_SIZE
+			|| area.end > USER_END
+			|| area.start >= area.end
+			|| !self.is_free(area.start..area.end)
+		{
+			return Err(MapError::BadRange);
+		}
+
+		self.insert(area);
+		Ok(())
+	}
+
+	/// Map the pages in `range` right away, it has to be mapped completely.
+	pub fn populate(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		range: Range<usize>,
+	) -> Result<(), MapError> {
+		let start = range.start & !(PAGE_SIZE - 1);
+		for page in (start..range.end).step_by(PAGE_SIZE) {
+			let Some(area) = self.find_mut(page) else {
+				return Err(MapError::BadRange);
+			};
+			area.populate(pml4, page)?;
+		}
+		Ok(())
+	}
+
+	/// Copy `data` to `addr` through the physical mapping, so read-only areas
+	/// can be filled too. The destination has to be mapped completely and not
+	/// shared copy-on-write, which only holds for a fresh address space.
+	pub fn write(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		addr: usize,
+		data: &[u8],
+	) -> Result<(), MapError> {
+		self.populate(pml4, addr..addr + data.len())?;
+
+		let mut done = 0;
+		while done < data.len() {
+			let dst = addr + done;
+			let len = min(PAGE_SIZE - dst % PAGE_SIZE, data.len() - done);
+			let frame = pml4.entry_mut(dst).unwrap().address();
+			unsafe {
+				ptr::copy_nonoverlapping(
+					data[done..].as_ptr(),
+					frame.offset(dst % PAGE_SIZE).to_virtual::<u8>(),
+					len,
+				)
+			};
+			done += len;
+		}
+		Ok(())
+	}
+
+	/// Whether the `len` bytes at `addr` lie in areas allowing `access`,
+	/// growing a stack down to them. The kernel checks user buffers with it
+	/// before touching them, its own faults there are fatal.
+	pub fn check(&mut self, addr: usize, len: usize, access: Access) -> bool {
+		if len == 0 {
+			return true;
+		}
+		let Some(end) = addr.checked_add(len).filter(|&end| end <= USER_END)
+		else {
+			return false;
+		};
+
+		let start = addr & !(PAGE_SIZE - 1);
+		(start..end).step_by(PAGE_SIZE).all(|page| {
+			if self.find(page).is_none() && self.grow_stack(page).is_err() {
+				return false;
+			}
+			self.find(page).is_some_and(|area| area.allows(access))
+		})
+	}
+
+	/// Resolve a fault on the unmapped page at `addr`, growing a stack if
+	/// `addr` lies right below one. Fails if no area allows `access` there.
+	pub fn fault(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		addr: usize,
+		access: Access,
+	) -> Result<(), MapError> {
+		let page = addr & !(PAGE_SIZE - 1);
+		if self.find(page).is_none() {
+			self.grow_stack(page)?;
+		}
+
+		let area = self.find_mut(page).unwrap();
+		if !area.allows(access) {
+			return Err(MapError::BadRange);
+		}
+		Ok(area.populate(pml4, page)?)
+	}
+
+	/// Remove every area in `range` and free their pages, areas partly in
+	/// `range` shrink.
+	pub fn unmap(&mut self, pml4: &mut PageTable<PML4>, range: Range<usize>) {
+		self.split(range.start);
+		self.split(range.end);
+
+		let starts: Vec<usize> = self
+			.areas
+			.range(range.clone())
+			.map(|(&start, _)| start)
+			.collect();
+		for start in starts {
+			let area = self.areas.remove(&start).unwrap();
+			self.release(pml4, area.start..area.end);
+		}
+	}
+
+	/// Change the protection of `range`, which has to be mapped completely.
+	pub fn protect(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		range: Range<usize>,
+		prot: i32,
+	) -> Result<(), MapError> {
+		if !self.is_mapped(range.clone()) {
+			return Err(MapError::BadRange);
+		}
+
+		self.split(range.start);
+		self.split(range.end);
+
+		for area in self.areas.range_mut(range).map(|(_, area)| area) {
+			area.prot = prot;
+			for page in (area.start..area.end).step_by(PAGE_SIZE) {
+				pml4.protect(page, area.page_flags());
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Start an empty heap at `addr`.
+	pub fn init_brk(&mut self, addr: usize) {
+		self.brk_start = addr;
+		self.brk = addr;
+	}
+
+	pub fn brk(&self) -> usize {
+		self.brk
+	}
+
+	/// Grow or shrink the heap so it ends at `addr`.
+	pub fn set_brk(
+		&mut self,
+		pml4: &mut PageTable<PML4>,
+		addr: usize,
+	) -> Result<(), MapError> {
+		if addr < self.brk_start {
+			return Err(MapError::BadRange);
+		}
+
+		let end = self.brk.next_multiple_of(PAGE_SIZE);
+		let new_end = addr.next_multiple_of(PAGE_SIZE);
+		if new_end > end {
+			let heap = Area::new(
+				end..new_end,
+				api::PROT_READ | api::PROT_WRITE,
+				Backing::Anonymous,
+			);
+			self.map(heap)?;
+		} else if new_end < end {
+			self.unmap(pml4, new_end..end);
+		}
+
+		self.brk = addr;
+		Ok(())
+	}
+
+	fn find_mut(&mut self, addr: usize) -> Option<&mut Area> {
+		self.areas
+			.range_mut(..=addr)
+			.next_back()
+			.map(|(_, area)| area)
+			.filter(|area| addr < area.end)
+	}
+
+	/// Extend the stack right above `page` down to it.
+	fn grow_stack(&mut self, page: usize) -> Result<(), MapError> {
+		let Some((&start, stack)) = self.areas.range(page..).next() else {
+			return Err(MapError::BadRange);
+		};
+		let end = self.stack_end(start);
+		if !matches!(stack.backing, Backing::Stack)
+			|| end - page > STACK_LIMIT
+			|| page < PAGE_SIZE
+			|| !self.is_free(page - PAGE_SIZE..start)
+		{
+			return Err(MapError::BadRange);
+		}
+
+		let mut stack = self.areas.remove(&start).unwrap();
+		stack.start = page;
+		self.areas.insert(page, stack);
+		Ok(())
+	}
+
+	/// End of the stack whose lowest area starts at `start`, mprotect() might
+	/// have split it.
+	fn stack_end(&self, start: usize) -> usize {
+		let mut end = start;
+		for area in self.areas.range(start..).map(|(_, area)| area) {
+			if area.start != end || !matches!(area.backing, Backing::Stack) {
+				break;
+			}
+			end = area.end;
+		}
+		end
+	}
+
+	fn is_free(&self, range: Range<usize>) -> bool {
+		self.areas
+			.range(..range.end)
+			.next_back()
+			.map_or(true, |(_, area)| area.end <= range.start)
+	}
+
+	fn is_mapped(&self, range: Range<usize>) -> bool {
+		let mut addr = range.start;
+		while addr < range.end {
+			match self.find(addr) {
+				Some(area) => addr = area.end,
+				None => return false,
+			}
+		}
+		true
+	}
+
+	/// Make `addr` an area boundary if it falls inside one.
+	fn split(&mut self, addr: usize) {
+		let Some(area) = self
+			.areas
+			.range_mut(..addr)
+			.next_back()
+			.map(|(_, area)| area)
+			.filter(|area| addr < area.end)
+		else {
+			return;
+		};
+
+		let upper = area.split_off(addr);
+		self.areas.insert(upper.start, upper);
+	}
+
+	/// Insert `area`, merging it into the anonymous area right below if they
+	/// only differ in range.
+	fn insert(&mut self, area: Area) {
+		if let Some((_, below)) = self.areas.range_mut(..area.start).next_back()
+		{
+			if below.end == area.start
+				&& below.prot == area.prot
+				&& matches!(below.backing, Backing::Anonymous)
+				&& matches!(area.backing, Backing::Anonymous)
+			{
+				below.end = area.end;
+				return;
+			}
+		}
+
+		self.areas.insert(area.start, area);
+	}
+
+	/// Unmap the pages in `range` and drop the frames they held.
+	fn release(&self, pml4: &mut PageTable<PML4>, range: Range<usize>) {
+		for page in range.step_by(PAGE_SIZE) {
+			if let Some(frame) = pml4.unmap(page) {
+				frame::current_mut().lock().release(frame);
+			}
+		}
+	}
+}
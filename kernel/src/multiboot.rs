@@ -1,4 +1,4 @@
-use crate::mem::PAGE_SIZE;
+use crate::mem::HUGE_PAGE_SIZE;
 
 const MEM_INFO_FLAG: u32 = 0x1;
 
@@ -65,6 +65,6 @@ pub struct MultibootMmapEntry {
 
 impl MultibootMmapEntry {
 	pub fn pages(&self) -> usize {
-		(self.len as usize + PAGE_SIZE - 1) / PAGE_SIZE
+		(self.len as usize + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE
 	}
 }
@@ -1,18 +1,24 @@
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use core::{
 	arch::asm,
-	ptr,
+	fmt::{Debug, Formatter},
+	mem::size_of,
+	ptr, slice, str,
 	sync::atomic::{AtomicU64, Ordering},
 };
 
-use log::{info, trace};
-use vmem::{Page, PageTable};
+use libc::api;
+use log::trace;
+use vmem::PageTable;
 
 use crate::{
-	arch::amd64::{cli, vmem, vmem::PML4},
-	fs,
-	fs::{device::inode::DeviceInode, fs0, inode::Inode, FileDescriptor},
-	mem::{frame, PhysicalAddress, KERNEL_VMA, PAGE_SIZE},
+	arch::amd64::{cli, gdt, vmem, vmem::PML4},
+	fs::{fs0, inode::Inode, FdTable, FileDescriptor},
+	mem::{
+		frame::OutOfMemory,
+		vma::{AddressSpace, Area, Backing, MapError},
+		PhysicalAddress, KERNEL_VMA,
+	},
 	syscall::RegisterState,
 };
 
@@ -58,110 +64,250 @@ impl CPU {
 		// TODO: Push/pop interrupt flag, though sysret will enable interrupts
 		//       anyway.
 		cli();
-		info!("new task: {next_task:#X?}");
+		trace!("switch to task {}", next_task.pid);
 		// Store current task.
 		self.task = next_task as *mut Task;
-		// Store user-space stack pointer.
-		self.rsp3 = next_task.register_state.rsp as usize;
+		// Syscalls and interrupts from user space land on the task's own
+		// kernel stack.
+		self.rsp0 = next_task.kernel_stack.top();
+		gdt::set_kernel_stack(self.rsp0);
 		// Switch page tables.
 		unsafe { asm!("mov cr3, {}", in(reg) next_task.cr3) };
 	}
 }
 
+pub struct KernelStack(Box<[u8]>);
+
+impl KernelStack {
+	const SIZE: usize = 0x4000;
+
+	fn new() -> Self {
+		Self(vec![0; Self::SIZE].into_boxed_slice())
+	}
+
+	pub fn top(&self) -> usize {
+		(self.0.as_ptr() as usize + self.0.len()) & !0xF
+	}
+}
+
+impl Debug for KernelStack {
+	fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+		write!(f, "KernelStack({:016X})", self.top())
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TaskState {
+	Runnable,
+	Blocked,
+	/// Exited and waiting to be reaped by its parent, holding the wait status.
+	Zombie(i32),
+}
+
 #[derive(Debug)]
 pub struct Task {
 	name: &'static str,
 	pub pid: u64,
+	pub parent: Option<u64>,
+	pub state: TaskState,
 	pub cwd: Inode,
-	pub open_files: Vec<fs::FileDescriptor>,
+	/// The arguments of the running program, each ending in a NUL.
+	pub cmdline: Vec<u8>,
+	pub open_files: FdTable,
 
 	pub cr3: usize,
 	pub register_state: RegisterState,
+	pub memory: AddressSpace,
 
-	pub next: *mut Task,
+	kernel_stack: KernelStack,
+	/// Saved kernel stack pointer while switched out (see switch.asm).
+	pub kernel_rsp: usize,
 }
 
 impl Task {
-	const STACK_SIZE: usize = 0x1000;
+	/// Initial size of the user stack, it grows down on faults below.
+	const STACK_SIZE: usize = 0x10000;
 	const STACK_ALIGN: usize = 0x1000;
 	pub const START_ADDR: usize = 0x200000;
-	pub const STACK_BOTTOM: usize = 0xFFFFFE8000000000;
+	pub const STACK_TOP: usize = 0x00007FFFFFF00000;
 
 	pub fn new(name: &'static str) -> Self {
-		let mut open_files = Vec::with_capacity(3);
-		open_files
-			.push(FileDescriptor::new(Inode::Device(DeviceInode::Console)));
-		open_files
-			.push(FileDescriptor::new(Inode::Device(DeviceInode::Console)));
-		open_files
-			.push(FileDescriptor::new(Inode::Device(DeviceInode::Serial)));
+		let console = fs0().find(fs0().root(), "/dev/tty0").unwrap();
+		let serial = fs0().find(fs0().root(), "/dev/com1").unwrap();
+		let mut open_files = FdTable::default();
+		for inode in [console.clone(), console, serial] {
+			let file = FileDescriptor::with_flags(inode, api::O_RDWR as i32);
+			open_files.insert(Rc::new(file), false);
+		}
+
+		// The root if there is no home, an initrd root may lack one.
+		let cwd = fs0()
+			.find(fs0().root(), "/home/default")
+			.unwrap_or_else(|| fs0().root().clone());
 
 		let mut fetus = Self {
 			pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
-			cwd: fs0().find(&fs0().root(), "/home/default").unwrap(),
+			parent: None,
+			state: TaskState::Runnable,
+			cwd,
+			cmdline: Vec::new(),
 			open_files,
 			name,
 			register_state: RegisterState::default(),
 			cr3: 0,
-			next: ptr::null_mut(),
+			memory: AddressSpace::default(),
+			kernel_stack: KernelStack::new(),
+			kernel_rsp: 0,
 		};
-		fetus.reimage();
+		fetus.reimage().expect("no memory for the first task");
 		fetus
 	}
 
-	pub fn reimage(&mut self) {
-		let mut falloc = frame::current_mut().lock();
+	/// The program name from `cmdline`.
+	pub fn name(&self) -> &str {
+		let argv0 = self.cmdline.split(|&byte| byte == 0).next();
+		match argv0.and_then(|argv0| str::from_utf8(argv0).ok()) {
+			Some(argv0) if !argv0.is_empty() => {
+				argv0.rsplit('/').next().unwrap_or(argv0)
+			}
+			_ => self.name,
+		}
+	}
 
+	/// Replace the address space with an empty one holding just a stack, the
+	/// ELF loader adds the program. Leaves the task untouched on failure.
+	pub fn reimage(&mut self) -> Result<(), MapError> {
 		// Copy the existing PML4 table, which maps the kernel already.
-		let pml4 = PageTable::<PML4>::new_with_kernel();
+		let pml4 = PageTable::<PML4>::new_with_kernel()?;
 		let cr3 = pml4 as *mut _ as usize;
 
-		// HACK: Programs better not exceed 2MB!!
-		let rbp = Self::STACK_BOTTOM;
-		let rsp = Self::STACK_BOTTOM + PAGE_SIZE - 16;
-		pml4.map(
-			falloc.alloc().0,
-			Self::START_ADDR,
-			Page::HUGE | Page::PRESENT | Page::USER | Page::READ_WRITE,
-			false,
-		);
-		pml4.map(
-			falloc.alloc().0,
-			Self::STACK_BOTTOM,
-			Page::PRESENT | Page::USER | Page::READ_WRITE,
-			false,
+		let mut memory = AddressSpace::default();
+		let stack = Area::new(
+			Self::STACK_TOP - Self::STACK_SIZE..Self::STACK_TOP,
+			api::PROT_READ | api::PROT_WRITE,
+			Backing::Stack,
 		);
+		if let Err(error) = memory.map(stack) {
+			pml4.free();
+			return Err(error);
+		}
 
-		self.register_state.rsp = rsp as u64;
-		self.register_state.rbp = rbp as u64;
+		self.register_state = RegisterState::default();
+		self.register_state.rsp = (Self::STACK_TOP - 16) as u64;
 		self.register_state.rip = Self::START_ADDR as u64;
+		self.memory = memory;
 		self.cr3 = cr3 - KERNEL_VMA;
+		Ok(())
+	}
+
+	/// Lay out the strings of `argv` and `envp` and the vectors pointing at
+	/// them, followed by `auxv`, on top of the user stack as the SysV ABI has
+	/// it at process entry. The address space has to be the current one.
+	pub fn init_stack(
+		&mut self,
+		argv: &[&[u8]],
+		envp: &[&[u8]],
+		auxv: &[(u64, u64)],
+	) -> Result<(), MapError> {
+		let pml4 = PageTable::<PML4>::current_mut();
+		let mut sp = Self::STACK_TOP;
+
+		let mut pointers = Vec::with_capacity(argv.len() + envp.len());
+		for string in argv.iter().chain(envp) {
+			sp -= string.len() + 1;
+			self.memory.write(pml4, sp, string)?;
+			self.memory.write(pml4, sp + string.len(), &[0])?;
+			pointers.push(sp as u64);
+		}
+		let (argv_pointers, envp_pointers) = pointers.split_at(argv.len());
+
+		let mut vector = vec![argv.len() as u64];
+		vector.extend(argv_pointers);
+		vector.push(0);
+		vector.extend(envp_pointers);
+		vector.push(0);
+		for &(key, value) in auxv {
+			vector.extend([key, value]);
+		}
+		// AT_NULL
+		vector.extend([0, 0]);
+
+		// The stack pointer is 16 byte aligned and points at argc.
+		sp = (sp - vector.len() * size_of::<u64>()) & !0xF;
+		let bytes = unsafe {
+			slice::from_raw_parts(
+				vector.as_ptr() as *const u8,
+				vector.len() * size_of::<u64>(),
+			)
+		};
+		self.memory.write(pml4, sp, bytes)?;
+
+		self.register_state.rsp = sp as u64;
+		self.cmdline = argv
+			.iter()
+			.flat_map(|arg| arg.iter().chain(&[0]))
+			.copied()
+			.collect();
+		Ok(())
 	}
 
-	pub fn fork(&mut self) -> &'static mut Task {
+	/// Copy this task, the child resumes from `regs` with a return value of 0.
+	/// Fails if there's no memory left for the child's page tables.
+	pub fn fork(
+		&mut self,
+		regs: &RegisterState,
+	) -> Result<Box<Task>, OutOfMemory> {
 		trace!("Task::fork()");
 
 		let pml4: &mut PageTable<PML4> =
 			unsafe { &mut *(PhysicalAddress(self.cr3).to_virtual()) };
-		let child_pml4 = pml4.fork();
+		let child_pml4 = pml4.fork()?;
 
-		let task = Self {
+		let mut register_state = regs.clone();
+		register_state.rax = 0;
+
+		Ok(Box::new(Self {
 			pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+			parent: Some(self.pid),
+			state: TaskState::Runnable,
 			cwd: self.cwd.clone(),
+			cmdline: self.cmdline.clone(),
 			open_files: self.open_files.clone(),
 			name: self.name,
-			register_state: self.register_state.clone(),
-			cr3: (child_pml4 as *mut PageTable<PML4> as usize) - KERNEL_VMA,
+			register_state,
+			memory: self.memory.clone(),
 			// to physical
-			next: ptr::null_mut(),
-		};
+			cr3: (child_pml4 as *mut PageTable<PML4> as usize) - KERNEL_VMA,
+			kernel_stack: KernelStack::new(),
+			kernel_rsp: 0,
+		}))
+	}
+
+	/// Lay out a fresh kernel stack so that the first switch to this task
+	/// returns to user space with `register_state` through `_syscall_exit`.
+	pub fn push_entry_frame(&mut self) {
+		let regs = self.kernel_stack.top() - size_of::<RegisterState>();
+		unsafe { ptr::write(regs as *mut RegisterState, self.register_state) };
+
+		// Callee-saved registers popped by switch_context(), then the address
+		// it returns to.
+		let frame = [0, 0, 0, 0, 0, 0, _syscall_exit as *const () as usize];
+		let rsp = regs - size_of::<[usize; 7]>();
+		unsafe { ptr::write(rsp as *mut [usize; 7], frame) };
 
-		unsafe { &mut *(Box::into_raw(Box::new(task))) }
+		self.kernel_rsp = rsp;
 	}
 }
 
+extern "C" {
+	fn _syscall_exit();
+}
+
 impl Drop for Task {
 	fn drop(&mut self) {
-		panic!("you dropped a task and didn't mean to");
+		trace!("drop task {}", self.pid);
+		let pml4: &mut PageTable<PML4> =
+			unsafe { &mut *(PhysicalAddress(self.cr3).to_virtual()) };
+		pml4.free();
 	}
 }
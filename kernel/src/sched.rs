@@ -0,0 +1,213 @@
+use alloc::{
+	boxed::Box,
+	collections::{BTreeMap, VecDeque},
+	vec::Vec,
+};
+
+use log::trace;
+
+use crate::{
+	arch::amd64::{cli, hlt, sti},
+	kalloc,
+	proc::{Task, TaskState, CPU},
+	sync::StaticPtr,
+};
+
+/// Orphaned tasks are handed to the first user process.
+const INIT_PID: u64 = 1;
+
+static SCHEDULER: StaticPtr<Scheduler> = StaticPtr::new();
+
+pub enum Reap {
+	/// A child exited with the given wait status and has been freed.
+	Exited(u64, i32),
+	/// Matching children exist but none have exited yet.
+	Running,
+	NoChildren,
+}
+
+/// Round-robin scheduler. Only ever touched with interrupts disabled or from
+/// syscalls, and the timer only preempts user space, so it needs no lock.
+#[derive(Debug)]
+pub struct Scheduler {
+	tasks: BTreeMap<u64, Box<Task>>,
+	run_queue: VecDeque<u64>,
+}
+
+impl Scheduler {
+	fn next(&mut self) -> &'static mut Task {
+		// Nothing is runnable, wait for an interrupt to change that.
+		while self.run_queue.is_empty() {
+			sti();
+			hlt();
+			cli();
+		}
+
+		let pid = self.run_queue.pop_front().unwrap();
+		let task = self.tasks.get_mut(&pid).expect("queued task is missing");
+		unsafe { &mut *(task.as_mut() as *mut Task) }
+	}
+
+	fn wake(&mut self, pid: u64) {
+		let Some(task) = self.tasks.get_mut(&pid) else {
+			return;
+		};
+		if task.state == TaskState::Blocked {
+			task.state = TaskState::Runnable;
+			self.run_queue.push_back(pid);
+		}
+	}
+}
+
+pub fn init() {
+	SCHEDULER.init(Scheduler {
+		tasks: BTreeMap::new(),
+		run_queue: VecDeque::new(),
+	});
+}
+
+/// Add a new task to the back of the run queue.
+pub fn spawn(mut task: Box<Task>) -> u64 {
+	task.push_entry_frame();
+
+	let pid = task.pid;
+	let scheduler = SCHEDULER.get();
+	scheduler.tasks.insert(pid, task);
+	scheduler.run_queue.push_back(pid);
+
+	trace!("spawn({pid})");
+	pid
+}
+
+/// Switch from the boot stack to the first task in the run queue.
+pub fn start() -> ! {
+	cli();
+	let mut boot_rsp = 0;
+	switch(&mut boot_rsp, SCHEDULER.get().next());
+	unreachable!("switched back to the boot stack");
+}
+
+/// Time-slice the current task, called from the timer interrupt when it
+/// interrupted user space.
+pub fn preempt() {
+	let scheduler = SCHEDULER.get();
+	if scheduler.run_queue.is_empty() {
+		return;
+	}
+
+	let current = CPU::load().current_task();
+	scheduler.run_queue.push_back(current.pid);
+	switch(&mut current.kernel_rsp, scheduler.next());
+}
+
+/// Give up the CPU while waiting in the kernel, halts until the next interrupt
+/// if there is nothing else to run.
+pub fn yield_now() {
+	cli();
+	let scheduler = SCHEDULER.get();
+	if scheduler.run_queue.is_empty() {
+		sti();
+		hlt();
+		return;
+	}
+
+	let current = CPU::load().current_task();
+	scheduler.run_queue.push_back(current.pid);
+	switch(&mut current.kernel_rsp, scheduler.next());
+	sti();
+}
+
+/// Sleep until another task wakes this one up.
+pub fn block() {
+	cli();
+	let current = CPU::load().current_task();
+	current.state = TaskState::Blocked;
+	switch(&mut current.kernel_rsp, SCHEDULER.get().next());
+	sti();
+}
+
+/// Make a blocked task runnable again.
+pub fn wake(pid: u64) {
+	SCHEDULER.get().wake(pid);
+}
+
+/// Turn the current task into a zombie holding `wait_status` and switch away
+/// from it for good. Its parent frees it in `reap()`.
+pub fn exit(wait_status: i32) -> ! {
+	cli();
+	let scheduler = SCHEDULER.get();
+	let current = CPU::load().current_task();
+
+	current.state = TaskState::Zombie(wait_status);
+	current.open_files.clear();
+
+	// Init reaps the orphans, wake it for those that already exited.
+	let mut orphaned_zombie = false;
+	for task in scheduler.tasks.values_mut() {
+		if task.parent == Some(current.pid) {
+			task.parent = Some(INIT_PID);
+			orphaned_zombie |= matches!(task.state, TaskState::Zombie(_));
+		}
+	}
+	if orphaned_zombie {
+		scheduler.wake(INIT_PID);
+	}
+
+	// A parent gone without reparenting this task leaves it to init too.
+	if let Some(mut parent) = current.parent {
+		if !scheduler.tasks.contains_key(&parent) {
+			parent = INIT_PID;
+			current.parent = Some(parent);
+		}
+		scheduler.wake(parent);
+	}
+
+	switch(&mut current.kernel_rsp, scheduler.next());
+	unreachable!("exited task was scheduled");
+}
+
+/// Free an exited child of `parent`, any child if `pid` is -1.
+pub fn reap(parent: u64, pid: isize) -> Reap {
+	let scheduler = SCHEDULER.get();
+
+	let mut children = scheduler
+		.tasks
+		.values()
+		.filter(|task| {
+			task.parent == Some(parent) && (pid == -1 || task.pid == pid as u64)
+		})
+		.peekable();
+	if children.peek().is_none() {
+		return Reap::NoChildren;
+	}
+
+	let Some((pid, status)) = children.find_map(|task| match task.state {
+		TaskState::Zombie(status) => Some((task.pid, status)),
+		_ => None,
+	}) else {
+		return Reap::Running;
+	};
+
+	trace!("reap({pid}), heap: {:?}", kalloc::stats());
+	scheduler.tasks.remove(&pid);
+	Reap::Exited(pid, status)
+}
+
+/// Pids of all tasks, zombies included.
+pub fn pids() -> Vec<u64> {
+	SCHEDULER.get().tasks.keys().copied().collect()
+}
+
+/// Call `f` with the task `pid`, None if there is no such task.
+pub fn with_task<T>(pid: u64, f: impl FnOnce(&Task) -> T) -> Option<T> {
+	SCHEDULER.get().tasks.get(&pid).map(|task| f(task))
+}
+
+fn switch(prev_rsp: &mut usize, next: &mut Task) {
+	CPU::load().switch_task(next);
+	unsafe { switch_context(prev_rsp, next.kernel_rsp) };
+}
+
+extern "C" {
+	fn switch_context(prev_rsp: *mut usize, next_rsp: usize);
+}
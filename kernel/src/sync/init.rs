@@ -35,6 +35,11 @@ impl<T> StaticPtr<T> {
 		}
 		unsafe { &mut *val }
 	}
+
+	pub fn try_get(&self) -> Option<&mut T> {
+		let val = self.0.load(Ordering::Acquire);
+		unsafe { val.as_mut() }
+	}
 }
 
 // TODO: make T Sync.
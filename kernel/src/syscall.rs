@@ -1,7 +1,5 @@
-use alloc::{format, string::String, vec};
-use core::{
-	arch::asm, cmp::min, ffi::CStr, ptr, slice, str,
-};
+use alloc::{rc::Rc, vec::Vec};
+use core::{cmp::min, ffi::CStr, mem::size_of, ptr, slice, str};
 
 use libc::api;
 use log::{debug, info, trace, warn};
@@ -9,19 +7,17 @@ use log::{debug, info, trace, warn};
 use crate::{
 	arch::amd64::{
 		clock, sti,
-		vmem::{
-			page_table_index, Page, PageTable, Table,
-			PML4,
-		},
+		vmem::{PageTable, Table, PML4},
 	},
-	elf,
-	fs::{
-		fs0,
-		inode::{Inode, Stat},
-		FileDescriptor,
+	elf::{Elf, ElfSource},
+	fs::{cache, fs0, inode::Inode, pipe, FileDescriptor},
+	mem::{
+		vma::{Access, Area, Backing, USER_END},
+		PhysicalAddress, PAGE_SIZE,
 	},
-	mem::{frame, PAGE_SIZE},
-	proc::{Task, CPU},
+	proc::CPU,
+	sched,
+	sched::Reap,
 };
 
 #[repr(C)]
@@ -50,10 +46,15 @@ pub struct RegisterState {
 pub unsafe extern "C" fn syscall_enter(regs: &mut RegisterState) {
 	trace!("syscall {}", regs.rax);
 	let ret = match regs.rax {
-		1 => sys_exit(regs.rdi as isize, regs),
-		2 => sys_brk(regs.rdi),
-		3 => sys_open(regs.rdi as *const u8, regs.rsi as usize),
-		4 => sys_stat(regs.rdi as usize),
+		1 => sys_exit(regs.rdi as isize),
+		2 => sys_brk(regs.rdi as usize),
+		3 => sys_open(
+			regs.rdi as *const u8,
+			regs.rsi as usize,
+			regs.rdx as i32,
+			regs.r10 as u16,
+		),
+		4 => sys_stat(regs.rdi as *const u8, regs.rsi as *mut api::stat),
 		5 => {
 			sys_read(regs.rdi as isize, regs.rsi as *mut u8, regs.rdx as usize)
 		}
@@ -62,18 +63,69 @@ pub unsafe extern "C" fn syscall_enter(regs: &mut RegisterState) {
 			regs.rsi as *const u8,
 			regs.rdx as usize,
 		),
-		7 => sys_readdir(regs.rdi as isize, regs.rsi as *mut api::dirent),
 		8 => sys_chdir(regs.rdi as *const u8, regs.rsi as usize),
-		9 => sys_fork(regs) as isize,
+		9 => sys_fork(regs),
 		10 => sys_fstat(regs.rdi as isize, regs.rsi as *mut api::stat) as isize,
 		11 => sys_getcwd(regs.rdi as *mut u8, regs.rsi as usize),
-		12 => sys_exec(regs.rdi as *mut u8, regs),
-		69 => sys_brk(regs.rdi),
+		12 => sys_exec(
+			regs.rdi as *mut u8,
+			regs.rsi as *const *const u8,
+			regs.rdx as *const *const u8,
+			regs,
+		),
+		13 => sys_waitpid(
+			regs.rdi as isize,
+			regs.rsi as *mut i32,
+			regs.rdx as i32,
+		),
+		14 => sys_mmap(
+			regs.rdi as usize,
+			regs.rsi as usize,
+			regs.rdx as i32,
+			regs.r10 as i32,
+			regs.r8 as isize,
+			regs.r9 as usize,
+		),
+		15 => sys_munmap(regs.rdi as usize, regs.rsi as usize),
+		16 => {
+			sys_mprotect(regs.rdi as usize, regs.rsi as usize, regs.rdx as i32)
+		}
+		17 => sys_mkdir(regs.rdi as *const u8, regs.rsi as u16),
+		18 => sys_rmdir(regs.rdi as *const u8),
+		19 => sys_unlink(regs.rdi as *const u8),
+		20 => sys_rename(regs.rdi as *const u8, regs.rsi as *const u8),
+		21 => sys_link(regs.rdi as *const u8, regs.rsi as *const u8),
+		22 => sys_sync(),
+		23 => sys_close(regs.rdi as isize),
+		24 => sys_dup(regs.rdi as isize),
+		25 => sys_dup2(regs.rdi as isize, regs.rsi as isize),
+		26 => sys_fcntl(regs.rdi as isize, regs.rsi as i32, regs.rdx as i32),
+		27 => sys_pipe(regs.rdi as *mut [i32; 2]),
+		28 => sys_lseek(regs.rdi as isize, regs.rsi as isize, regs.rdx as i32),
+		29 => sys_pread(
+			regs.rdi as isize,
+			regs.rsi as *mut u8,
+			regs.rdx as usize,
+			regs.r10 as isize,
+		),
+		30 => sys_pwrite(
+			regs.rdi as isize,
+			regs.rsi as *const u8,
+			regs.rdx as usize,
+			regs.r10 as isize,
+		),
+		31 => sys_lstat(regs.rdi as *const u8, regs.rsi as *mut api::stat),
+		32 => sys_getdents64(
+			regs.rdi as isize,
+			regs.rsi as *mut u8,
+			regs.rdx as usize,
+		),
+		69 => sys_brk(regs.rdi as usize),
 		401 => uptime() as isize,
 		403 => debug_long(regs.rdi),
 		_ => {
 			warn!("unknown syscall: {}", regs.rax);
-			-1
+			error(api::ENOSYS)
 		}
 	};
 
@@ -81,6 +133,18 @@ pub unsafe extern "C" fn syscall_enter(regs: &mut RegisterState) {
 	trace!("sysret {}", regs.rax);
 }
 
+/// What a failed call returns, the negated error number. libc returns -1 and
+/// sets `errno` from it.
+fn error(errno: i32) -> isize {
+	-(errno as isize)
+}
+
+/// Whether the current task can `access` the `len` bytes at `ptr`.
+fn user_buffer<T>(ptr: *const T, len: usize, access: Access) -> bool {
+	let task = CPU::load().current_task();
+	task.memory.check(ptr as usize, len, access)
+}
+
 fn uptime() -> u64 {
 	clock::uptime_seconds()
 }
@@ -93,9 +157,13 @@ fn debug_long(long: u64) -> isize {
 fn sys_read(fd: isize, ptr: *mut u8, len: usize) -> isize {
 	let cpu = CPU::load();
 	let task = unsafe { &mut *cpu.task };
-	let Some(fildes) = task.open_files.get_mut(fd as usize) else {
-		return -1;
+	let Some(fildes) = task.open_files.get(fd).filter(|fd| fd.is_readable())
+	else {
+		return error(api::EBADF);
 	};
+	if !user_buffer(ptr, len, Access::Write) {
+		return error(api::EFAULT);
+	}
 
 	kdbg!(fildes.read(ptr, len) as isize)
 }
@@ -103,223 +171,654 @@ fn sys_read(fd: isize, ptr: *mut u8, len: usize) -> isize {
 fn sys_chdir(path: *const u8, len: usize) -> isize {
 	let cpu = CPU::load();
 	let task = unsafe { &mut *cpu.task };
+	if !user_buffer(path, len, Access::Read) {
+		return error(api::EFAULT);
+	}
 	let path = unsafe {
 		let slice = slice::from_raw_parts(path, len);
 		str::from_utf8_unchecked(slice)
 	};
 
-	if let Some(inode) = fs0().find(&task.cwd, path) {
-		task.cwd = inode;
-		0
-	} else {
-		-1
+	match fs0().find(&task.cwd, path) {
+		Some(inode) if inode.is_dir() => {
+			task.cwd = inode;
+			0
+		}
+		Some(_) => error(api::ENOTDIR),
+		None => error(api::ENOENT),
 	}
 }
 
-fn sys_readdir(fd: isize, ptr: *mut api::dirent) -> isize {
-	let cpu = CPU::load();
-	let task = unsafe { &mut *cpu.task };
-	let Some(fildes) = task.open_files.get_mut(fd as usize) else {
-		return -1;
+fn sys_getdents64(fildes: isize, buf: *mut u8, len: usize) -> isize {
+	let task = CPU::load().current_task();
+	let Some(file) = task.open_files.get(fildes) else {
+		return error(api::EBADF);
 	};
-	fildes.readdir(ptr);
-	0
+	if buf.is_null() || !user_buffer(buf, len, Access::Write) {
+		return error(api::EFAULT);
+	}
+	if !file.inode.is_dir() {
+		return error(api::ENOTDIR);
+	}
+	// The next entry doesn't fit.
+	file.getdents(buf, len)
+		.map_or(error(api::EINVAL), |used| used as isize)
 }
 
 fn sys_write(fd: isize, ptr: *const u8, len: usize) -> isize {
 	trace!("sys_write({:016X?}, {len})", ptr as u64);
 	let cpu = CPU::load();
 	let task = unsafe { &mut *cpu.task };
-	let Some(fd) = task.open_files.get_mut(fd as usize) else {
-		return -1;
+	let Some(fd) = task.open_files.get(fd).filter(|fd| fd.is_writable()) else {
+		return error(api::EBADF);
+	};
+	if !user_buffer(ptr, len, Access::Read) {
+		return error(api::EFAULT);
+	}
+	let Some(written) = fd.write(ptr, len) else {
+		return error(api::EFBIG);
+	};
+	if written == 0 && len > 0 && pipe::is_broken(&fd.inode) {
+		return error(api::EPIPE);
+	}
+	written as isize
+}
+
+/// Returns the new offset.
+fn sys_lseek(fildes: isize, offset: isize, whence: i32) -> isize {
+	let task = CPU::load().current_task();
+	let Some(file) = task.open_files.get(fildes) else {
+		return error(api::EBADF);
+	};
+	if !file.is_seekable() {
+		return error(api::ESPIPE);
+	}
+	match file.seek(offset, whence) {
+		Some(offset) => offset as isize,
+		None => error(api::EINVAL),
+	}
+}
+
+fn sys_pread(fildes: isize, ptr: *mut u8, len: usize, offset: isize) -> isize {
+	let task = CPU::load().current_task();
+	let Some(file) = task.open_files.get(fildes).filter(|fd| fd.is_readable())
+	else {
+		return error(api::EBADF);
+	};
+	if !file.is_seekable() {
+		return error(api::ESPIPE);
+	}
+	if offset < 0 {
+		return error(api::EINVAL);
+	}
+	if !user_buffer(ptr, len, Access::Write) {
+		return error(api::EFAULT);
+	}
+	file.pread(offset as usize, ptr, len) as isize
+}
+
+fn sys_pwrite(
+	fildes: isize,
+	ptr: *const u8,
+	len: usize,
+	offset: isize,
+) -> isize {
+	let task = CPU::load().current_task();
+	let Some(file) = task.open_files.get(fildes).filter(|fd| fd.is_writable())
+	else {
+		return error(api::EBADF);
 	};
-	fd.write(ptr, len) as isize
+	if !file.is_seekable() {
+		return error(api::ESPIPE);
+	}
+	if offset < 0 {
+		return error(api::EINVAL);
+	}
+	if !user_buffer(ptr, len, Access::Read) {
+		return error(api::EFAULT);
+	}
+	match file.pwrite(offset as usize, ptr, len) {
+		Some(written) => written as isize,
+		None => error(api::EFBIG),
+	}
 }
 
-fn sys_open(path: *const u8, len: usize) -> isize {
-	trace!("sys_open({path:?}, {len})");
+fn sys_open(path: *const u8, len: usize, oflag: i32, mode: u16) -> isize {
+	trace!("sys_open({path:?}, {len}, {oflag:#o}, {mode:#o})");
 
+	if !user_buffer(path, len, Access::Read) {
+		return error(api::EFAULT);
+	}
 	let slice = unsafe { slice::from_raw_parts(path, len) };
 	let fname = str::from_utf8(slice).expect("Invalid UTF-8 string");
 
 	let cpu = CPU::load();
 	let task = unsafe { &mut *cpu.task };
 
-	let Some(inode) = fs0().find(&task.cwd, fname) else {
-		return -1;
+	let create = oflag & api::O_CREAT as i32 != 0;
+	let inode = match fs0().find(&task.cwd, fname) {
+		Some(_) if create && oflag & api::O_EXCL as i32 != 0 => {
+			return error(api::EEXIST)
+		}
+		Some(inode) => inode,
+		None if create => {
+			let mode = api::S_IFREG as u16 | mode & 0o777;
+			let Some((dir, name)) = fs0().find_parent(&task.cwd, fname) else {
+				return error(api::ENOENT);
+			};
+			let Some(inode) = dir.create(name, mode) else {
+				return error(api::EPERM);
+			};
+			inode
+		}
+		None => return error(api::ENOENT),
 	};
-	let fdesc = FileDescriptor::new(inode);
-	task.open_files.push(fdesc);
 
-	return kdbg!(task.open_files.len() as isize - 1);
+	let writable = oflag & api::O_ACCMODE as i32 != api::O_RDONLY as i32;
+	let truncate = oflag & api::O_TRUNC as i32 != 0 && writable;
+	if truncate && !inode.is_dir() && inode.truncate(0).is_none() {
+		return error(api::EPERM);
+	}
+
+	let fdesc = Rc::new(FileDescriptor::with_flags(inode, oflag));
+	let cloexec = oflag & api::O_CLOEXEC as i32 != 0;
+	match task.open_files.insert(fdesc, cloexec) {
+		Some(fd) => kdbg!(fd as isize),
+		None => error(api::EMFILE),
+	}
 }
 
-fn sys_stat(fd: usize) -> isize {
-	let cpu = CPU::load();
-	let task = unsafe { &mut *cpu.task };
-	debug!("{:#X?}", task.open_files.get(fd));
+/// Store the read and write end of a new pipe in `fildes[0]` and `fildes[1]`.
+fn sys_pipe(fildes: *mut [i32; 2]) -> isize {
+	if !user_buffer(fildes, size_of::<[i32; 2]>(), Access::Write) {
+		return error(api::EFAULT);
+	}
+
+	let files = &mut CPU::load().current_task().open_files;
+	let (read, write) = pipe::pipe();
+	let read = FileDescriptor::with_flags(read, api::O_RDONLY as i32);
+	let write = FileDescriptor::with_flags(write, api::O_WRONLY as i32);
+	let Some(read) = files.insert(Rc::new(read), false) else {
+		return error(api::EMFILE);
+	};
+	let Some(write) = files.insert(Rc::new(write), false) else {
+		files.close(read as isize);
+		return error(api::EMFILE);
+	};
+
+	unsafe { *fildes = [read as i32, write as i32] };
 	0
 }
 
-fn sys_exit(status: isize, regs: &mut RegisterState) -> isize {
-	let cpu = CPU::load();
-	let task = unsafe { &mut *cpu.task };
+fn sys_close(fildes: isize) -> isize {
+	let task = CPU::load().current_task();
+	match task.open_files.close(fildes) {
+		Some(()) => 0,
+		None => error(api::EBADF),
+	}
+}
 
-	info!("process {} exited with status: {status}", task.pid);
+fn sys_dup(fildes: isize) -> isize {
+	let task = CPU::load().current_task();
+	if task.open_files.get(fildes).is_none() {
+		return error(api::EBADF);
+	}
+	match task.open_files.dup(fildes) {
+		Some(fd) => fd as isize,
+		None => error(api::EMFILE),
+	}
+}
 
-	if task.next.is_null() {
-		breakpoint!();
+fn sys_dup2(fildes: isize, fildes2: isize) -> isize {
+	let task = CPU::load().current_task();
+	match task.open_files.dup2(fildes, fildes2) {
+		Some(fd) => fd as isize,
+		None => error(api::EBADF),
 	}
+}
 
-	let next_task = unsafe { &mut *task.next };
+/// Only the descriptor flags, `F_GETFD` and `F_SETFD`.
+fn sys_fcntl(fildes: isize, cmd: i32, arg: i32) -> isize {
+	let files = &mut CPU::load().current_task().open_files;
+	if files.get(fildes).is_none() {
+		return error(api::EBADF);
+	}
+	let done = if cmd == api::F_GETFD as i32 {
+		files.cloexec(fildes).map(|cloexec| match cloexec {
+			true => api::FD_CLOEXEC as isize,
+			false => 0,
+		})
+	} else if cmd == api::F_SETFD as i32 {
+		let cloexec = arg & api::FD_CLOEXEC as i32 != 0;
+		files.set_cloexec(fildes, cloexec).map(|()| 0)
+	} else {
+		None
+	};
+	done.unwrap_or(error(api::EINVAL))
+}
+
+/// The NUL terminated path at `path`, None if it isn't UTF-8.
+fn user_path<'a>(path: *const u8) -> Option<&'a str> {
+	if path.is_null() {
+		return None;
+	}
+	unsafe { CStr::from_ptr(path as *const i8) }.to_str().ok()
+}
 
-	// Restore this task's register state.
-	debug!(
-		"next: {:016X?}, current: {:016X?}",
-		&next_task.register_state as *const RegisterState as usize,
-		regs as *mut RegisterState as usize
-	);
-	unsafe { ptr::write(regs as *mut RegisterState, next_task.register_state) };
+/// The directory `path` would be in, and its name there.
+fn parent_of(path: *const u8) -> Option<(Inode, &'static str)> {
+	let task = CPU::load().current_task();
+	fs0().find_parent(&task.cwd, user_path(path)?)
+}
 
-	cpu.switch_task(next_task);
+fn sys_mkdir(path: *const u8, mode: u16) -> isize {
+	trace!("sys_mkdir({path:?}, {mode:#o})");
+	let Some((dir, name)) = parent_of(path) else {
+		return error(api::ENOENT);
+	};
+	if dir.lookup(name).is_some() {
+		return error(api::EEXIST);
+	}
+	dir.create(name, api::S_IFDIR as u16 | mode & 0o777)
+		.map_or(error(api::EPERM), |_| 0)
+}
 
-	// HACK: syscall handler should not replace contents of RAX for every(/any?)
-	// syscall.
-	next_task.register_state.rax as isize
+fn sys_rmdir(path: *const u8) -> isize {
+	trace!("sys_rmdir({path:?})");
+	let Some((dir, name)) = parent_of(path) else {
+		return error(api::ENOENT);
+	};
+	let Some(entry) = dir.lookup(name) else {
+		return error(api::ENOENT);
+	};
+	if !entry.is_dir() {
+		return error(api::ENOTDIR);
+	}
+	let has_entries = entry
+		.readdir()
+		.iter()
+		.any(|entry| entry.name != "." && entry.name != "..");
+	if has_entries {
+		return error(api::ENOTEMPTY);
+	}
+	dir.rmdir(name).map_or(error(api::EPERM), |_| 0)
 }
 
-fn sys_brk(addr: u64) -> isize {
-	let cpu = CPU::load();
-	let task = cpu.current_task();
-
-	let (i_pml4, i_pdp, i_pd) =
-		page_table_index(task.register_state.rip as usize);
-	let pd = PageTable::<PML4>::current_mut()
-		.next_mut(i_pml4)
-		.expect("unmapped page that should definitely be mapped")
-		.next_mut(i_pdp)
-		.expect("unmapped page that should definitely be mapped");
-
-	for i in i_pd..512 {
-		if !pd[i].has(Page::PRESENT) {
-			let brk = (i * PAGE_SIZE) as isize;
-
-			if addr == 0 {
-				trace!("brk: {brk:016X}");
-			} else {
-				// TODO: Move this whole mess and implement mmap instead.
-				let mut frame_allocator = frame::current_mut().lock();
-				pd[i] = Page::new(
-					frame_allocator.alloc(),
-					Page::READ_WRITE | Page::HUGE | Page::USER | Page::PRESENT,
-				);
-				unsafe {
-					// TODO: This only works if this PD is at PDP[0], PML4[0].
-					asm!("invlpg [{}]", in(reg) brk);
-				};
+fn sys_unlink(path: *const u8) -> isize {
+	trace!("sys_unlink({path:?})");
+	let Some((dir, name)) = parent_of(path) else {
+		return error(api::ENOENT);
+	};
+	match dir.lookup(name) {
+		Some(entry) if entry.is_dir() => error(api::EISDIR),
+		Some(_) => dir.unlink(name).map_or(error(api::EPERM), |_| 0),
+		None => error(api::ENOENT),
+	}
+}
+
+fn sys_rename(old: *const u8, new: *const u8) -> isize {
+	trace!("sys_rename({old:?}, {new:?})");
+	let (Some((from, name)), Some((to, new_name))) =
+		(parent_of(old), parent_of(new))
+	else {
+		return error(api::ENOENT);
+	};
+	if from.lookup(name).is_none() {
+		return error(api::ENOENT);
+	}
+	if from.key().fs != to.key().fs {
+		return error(api::EXDEV);
+	}
+	from.rename(name, &to, new_name)
+		.map_or(error(api::EPERM), |_| 0)
+}
+
+/// Make `new` another name for the file at `existing`.
+fn sys_link(existing: *const u8, new: *const u8) -> isize {
+	trace!("sys_link({existing:?}, {new:?})");
+	let task = CPU::load().current_task();
+	let Some(target) =
+		user_path(existing).and_then(|path| fs0().find(&task.cwd, path))
+	else {
+		return error(api::ENOENT);
+	};
+	let Some((dir, name)) = parent_of(new) else {
+		return error(api::ENOENT);
+	};
+	if dir.lookup(name).is_some() {
+		return error(api::EEXIST);
+	}
+	if dir.key().fs != target.key().fs {
+		return error(api::EXDEV);
+	}
+	dir.link(name, &target).map_or(error(api::EPERM), |_| 0)
+}
+
+fn sys_sync() -> isize {
+	cache::sync();
+	0
+}
+
+fn sys_stat(path: *const u8, buf: *mut api::stat) -> isize {
+	let task = CPU::load().current_task();
+	let Some(inode) =
+		user_path(path).and_then(|path| fs0().find(&task.cwd, path))
+	else {
+		return error(api::ENOENT);
+	};
+	fill_stat(&inode, buf)
+}
+
+/// There are no symbolic links, so it's the same as stat().
+fn sys_lstat(path: *const u8, buf: *mut api::stat) -> isize {
+	sys_stat(path, buf)
+}
+
+fn sys_exit(status: isize) -> isize {
+	let task = CPU::load().current_task();
+
+	info!("process {} exited with status: {status}", task.pid);
+
+	sched::exit(((status & 0xFF) << 8) as i32)
+}
+
+fn sys_waitpid(pid: isize, stat_loc: *mut i32, options: i32) -> isize {
+	let task = CPU::load().current_task();
+	if !stat_loc.is_null()
+		&& !user_buffer(stat_loc, size_of::<i32>(), Access::Write)
+	{
+		return error(api::EFAULT);
+	}
+
+	loop {
+		match sched::reap(task.pid, pid) {
+			Reap::Exited(child, status) => {
+				if !stat_loc.is_null() {
+					unsafe { *stat_loc = status };
+				}
+				return child as isize;
 			}
+			Reap::Running if options & api::WNOHANG != 0 => return 0,
+			// Woken up by a child exiting.
+			Reap::Running => sched::block(),
+			Reap::NoChildren => return error(api::ECHILD),
+		}
+	}
+}
+
+/// Move the end of the heap to `addr` and return it, or just return it if
+/// `addr` is 0.
+fn sys_brk(addr: usize) -> isize {
+	let task = CPU::load().current_task();
 
-			return brk;
+	if addr != 0 {
+		let pml4 = PageTable::<PML4>::current_mut();
+		if task.memory.set_brk(pml4, addr).is_err() {
+			return error(api::ENOMEM);
 		}
 	}
 
-	-1
+	trace!("brk: {:016X}", task.memory.brk());
+	task.memory.brk() as isize
 }
 
-fn sys_fork(regs: &mut RegisterState) -> usize {
-	let cpu = CPU::load();
+fn sys_mmap(
+	addr: usize,
+	len: usize,
+	prot: i32,
+	flags: i32,
+	fildes: isize,
+	offset: usize,
+) -> isize {
+	let task = CPU::load().current_task();
 
-	let task = cpu.current_task();
+	if len == 0 || offset % PAGE_SIZE != 0 {
+		return error(api::EINVAL);
+	}
+	// Shared mappings would need writing back to files and sharing across
+	// fork().
+	if flags & api::MAP_PRIVATE == 0 {
+		return error(api::EINVAL);
+	}
+	let Some(len) = len
+		.checked_next_multiple_of(PAGE_SIZE)
+		.filter(|&len| len <= USER_END)
+	else {
+		return error(api::ENOMEM);
+	};
 
-	// Save current task's state.
-	task.register_state = regs.clone();
-	// Stack pointer in regs is the kernel's, take the user one from GS??
-	task.register_state.rsp = cpu.rsp3 as u64;
+	let backing = if flags & api::MAP_ANONYMOUS != 0 {
+		Backing::Anonymous
+	} else {
+		// Pages are read in from the page fault handler, which can't wait
+		// on a pipe or a terminal.
+		let Some(file) = task.open_files.get(fildes) else {
+			return error(api::EBADF);
+		};
+		if !file.is_readable() {
+			return error(api::EACCES);
+		}
+		if !file.is_seekable() || file.inode.is_dir() {
+			return error(api::ENODEV);
+		}
+		Backing::File(FileDescriptor::clone(file), offset)
+	};
+
+	let pml4 = PageTable::<PML4>::current_mut();
+	let start = if flags & api::MAP_FIXED != 0 {
+		// Check the area fits before unmapping what's there.
+		let Some(end) = addr.checked_add(len).filter(|&end| end <= USER_END)
+		else {
+			return error(api::EINVAL);
+		};
+		if addr % PAGE_SIZE != 0 || addr < PAGE_SIZE {
+			return error(api::EINVAL);
+		}
+		task.memory.unmap(pml4, addr..end);
+		addr
+	} else {
+		let Some(start) = task.memory.find_free(len) else {
+			return error(api::ENOMEM);
+		};
+		start
+	};
 
-	let new_task = task.fork();
-	// Set child pid as return value for parent task.
-	task.register_state.rax = new_task.pid;
+	let area = Area::new(start..start + len, prot, backing);
+	match task.memory.map(area) {
+		Ok(()) => start as isize,
+		Err(_) => error(api::ENOMEM),
+	}
+}
 
-	// Set CPU's active task.
-	// TODO: structure for multitasking
-	new_task.next = task as *mut Task;
-	cpu.switch_task(new_task);
+fn sys_munmap(addr: usize, len: usize) -> isize {
+	if addr % PAGE_SIZE != 0 || len == 0 {
+		return error(api::EINVAL);
+	}
 
-	// Return 0 to child.
+	let Some(end) = page_end(addr, len) else {
+		return error(api::EINVAL);
+	};
+
+	let task = CPU::load().current_task();
+	let pml4 = PageTable::<PML4>::current_mut();
+	task.memory.unmap(pml4, addr..end);
 	0
 }
 
+fn sys_mprotect(addr: usize, len: usize, prot: i32) -> isize {
+	if addr % PAGE_SIZE != 0 {
+		return error(api::EINVAL);
+	}
+
+	let Some(end) = page_end(addr, len) else {
+		return error(api::ENOMEM);
+	};
+
+	let task = CPU::load().current_task();
+	let pml4 = PageTable::<PML4>::current_mut();
+	// Fails where nothing is mapped.
+	match task.memory.protect(pml4, addr..end, prot) {
+		Ok(()) => 0,
+		Err(_) => error(api::ENOMEM),
+	}
+}
+
+/// The end of `len` bytes from `addr` rounded up to a page, None if it
+/// overflows.
+fn page_end(addr: usize, len: usize) -> Option<usize> {
+	addr.checked_add(len.checked_next_multiple_of(PAGE_SIZE)?)
+}
+
+fn sys_fork(regs: &mut RegisterState) -> isize {
+	let task = CPU::load().current_task();
+
+	// Save current task's state.
+	task.register_state = regs.clone();
+
+	// The child returns 0 when it is first scheduled, the parent gets its pid.
+	let Ok(child) = task.fork(regs) else {
+		return error(api::ENOMEM);
+	};
+	sched::spawn(child) as isize
+}
+
 fn sys_fstat(fildes: isize, buf: *mut api::stat) -> isize {
 	let task = CPU::load().current_task();
-	let Some(fd) = task.open_files.get_mut(fildes as usize) else {
-		return -1;
+	let Some(fd) = task.open_files.get(fildes) else {
+		return error(api::EBADF);
 	};
+	fill_stat(&fd.inode, buf)
+}
 
-	let out = unsafe { &mut *buf };
-	out.st_mode = fd.inode.mode();
-	out.st_size = fd.inode.size() as api::off_t;
+fn fill_stat(inode: &Inode, buf: *mut api::stat) -> isize {
+	if !user_buffer(buf, size_of::<api::stat>(), Access::Write) {
+		return error(api::EFAULT);
+	}
 
+	let stat = inode.stat();
+	let key = inode.key();
+	let out = unsafe { &mut *buf };
+	out.st_dev = key.fs as api::dev_t;
+	out.st_ino = key.inumber as api::ino_t;
+	out.st_mode = stat.mode;
+	out.st_nlink = stat.links as api::nlink_t;
+	out.st_uid = stat.uid;
+	out.st_gid = stat.gid;
+	out.st_rdev = 0;
+	out.st_size = stat.size as api::off_t;
+	out.st_blksize = PAGE_SIZE as api::blksize_t;
+	out.st_blocks = stat.blocks as api::blkcnt_t;
+	out.st_atime = stat.atime as api::time_t;
+	out.st_mtime = stat.mtime as api::time_t;
+	out.st_ctime = stat.ctime as api::time_t;
 	0
 }
 
 fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
 	let task = CPU::load().current_task();
-	let current_inode = &task.cwd;
-
-	fn prepend_parent(child: &Inode) -> Option<String> {
-		if let Some(parent) = child.parent() {
-			Some(format!(
-				"{}/{}",
-				prepend_parent(&parent).unwrap_or_default(),
-				child.name()
-			))
-		} else {
-			None
-		}
+	if !user_buffer(buf, len, Access::Write) {
+		return error(api::EFAULT);
 	}
-
-	let s = prepend_parent(current_inode).unwrap_or(String::from("/"));
+	let s = fs0().path(&task.cwd);
 	unsafe { ptr::copy_nonoverlapping(s.as_ptr(), buf, min(len, s.len())) };
 	0
 }
 
-fn sys_exec(pathname: *mut u8, regs: &mut RegisterState) -> isize {
+/// Bytes of arguments and environment strings, including their pointers,
+/// exec() copies to the new stack.
+const ARG_MAX: usize = 0x8000;
+
+/// Copy the NULL terminated array of C strings at `strings` into the kernel,
+/// counting their size against `budget`.
+fn copy_strings(
+	strings: *const *const u8,
+	budget: &mut usize,
+) -> Option<Vec<Vec<u8>>> {
+	let mut copies = Vec::new();
+	if strings.is_null() {
+		return Some(copies);
+	}
+
+	for i in 0.. {
+		let string = unsafe { *strings.add(i) };
+		if string.is_null() {
+			break;
+		}
+
+		let bytes = unsafe { CStr::from_ptr(string as *const i8) }.to_bytes();
+		*budget = budget.checked_sub(bytes.len() + 1 + size_of::<usize>())?;
+		copies.push(bytes.to_vec());
+	}
+	Some(copies)
+}
+
+fn sys_exec(
+	pathname: *mut u8,
+	argv: *const *const u8,
+	envp: *const *const u8,
+	regs: &mut RegisterState,
+) -> isize {
 	if pathname.is_null() {
-		return -1;
+		return error(api::EFAULT);
 	}
 
 	let Ok(path) = unsafe { CStr::from_ptr(pathname as *const i8) }.to_str()
 	else {
-		return -1;
+		return error(api::ENOENT);
+	};
+
+	// They live in the image that is about to be replaced.
+	let mut budget = ARG_MAX;
+	let Some(argv) = copy_strings(argv, &mut budget) else {
+		return error(api::E2BIG);
+	};
+	let Some(envp) = copy_strings(envp, &mut budget) else {
+		return error(api::E2BIG);
 	};
 
 	let task = CPU::load().current_task();
 	let current_inode = &task.cwd;
 	let Some(exec_inode) = fs0().find(current_inode, path) else {
-		return -1;
+		return error(api::ENOENT);
 	};
 
 	info!("Found path: {}", exec_inode.name());
 	if exec_inode.is_dir() {
-		return -1;
+		return error(api::EACCES);
 	}
 
+	// Check the headers up front, so a bad binary fails exec() before the
+	// current image is gone.
+	let source = ElfSource::File(FileDescriptor::new(exec_inode));
+	let mut elf = match Elf::parse(source) {
+		Ok(elf) => elf,
+		Err(err) => {
+			warn!("exec({path}): {err:?}");
+			return error(api::ENOEXEC);
+		}
+	};
+
 	// Replace current task with a new page table mapping.
-	task.reimage();
+	let old_cr3 = task.cr3;
+	if task.reimage().is_err() {
+		return error(api::ENOMEM);
+	}
 	// switch_task() to load the new page table mainly.
 	CPU::load().switch_task(task);
-	// HACK: switch_task() disables interrupts, but we need them to load the
-	//       binary.
+	// Drop this task's references to the old image's frames.
+	unsafe { &mut *PhysicalAddress(old_cr3).to_virtual::<PageTable<PML4>>() }
+		.free();
+	// switch_task() disables interrupts.
 	sti();
-
-	// Load binary into task's memory.
-	let mut fildes = FileDescriptor::new(exec_inode);
-	let sz = fildes.inode.size();
-	// HACK: Vec<usize> instead of Vec<u8> is for pointer aligment.
-	let mut buf = vec![0usize; sz / usize::BITS as usize];
-	fildes.read(buf.as_mut_ptr() as *mut u8, sz);
-
-	elf::load(buf.as_ptr() as *const u8, task);
+	task.open_files.close_on_exec();
+
+	let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+	let envp: Vec<&[u8]> = envp.iter().map(Vec::as_slice).collect();
+	if let Err(error) = elf.load(task, &argv, &envp) {
+		// The old image is gone, there is nothing to return to. So is `path`.
+		warn!("exec: {error:?}");
+		sched::exit(api::SIGKILL);
+	}
 
 	// Set up sysret to restore the new register state.
 	unsafe { ptr::write(regs as *mut RegisterState, task.register_state) };
@@ -4,7 +4,27 @@
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
-#[repr(C)]
+/// Bytes of entries a `DIR` reads ahead.
+const DIRBUF_LEN: usize = 0x1000;
+
+/// Aligned for the `dirent` records `readdir()` hands out of `buf`.
+#[repr(C, align(8))]
 pub struct __dirstream {
 	pub(crate) fd: isize,
+	/// Where the next record starts in `buf`.
+	pub(crate) pos: usize,
+	/// Where the records read into `buf` end.
+	pub(crate) end: usize,
+	pub(crate) buf: [u8; DIRBUF_LEN],
+}
+
+impl __dirstream {
+	pub(crate) fn new(fd: isize) -> Self {
+		Self {
+			fd,
+			pos: 0,
+			end: 0,
+			buf: [0; DIRBUF_LEN],
+		}
+	}
 }
@@ -1,23 +1,59 @@
-use alloc::{alloc::alloc, boxed::Box};
+use alloc::boxed::Box;
 use core::{
-	alloc::Layout,
-	ffi::{c_char, CStr},
+	ffi::{c_char, c_int, c_void, CStr},
+	ptr,
 };
 
-use crate::{api, api::__dirstream, syscall};
+use crate::{api, api::__dirstream, errno, syscall, unistd::close};
 
 #[no_mangle]
 pub extern "C" fn opendir(path: *const c_char) -> *mut api::DIR {
 	let c_str = unsafe { CStr::from_ptr(path) }.to_bytes();
-	let fd = syscall::open(c_str.as_ptr(), c_str.len());
-	Box::into_raw(Box::new(__dirstream { fd }))
+	let fd =
+		syscall::open(c_str.as_ptr(), c_str.len(), api::O_RDONLY as i32, 0);
+	if errno::check(fd) < 0 {
+		return ptr::null_mut();
+	}
+	Box::into_raw(Box::new(__dirstream::new(fd)))
 }
 
+/// The next entry, valid until the following `readdir()` or `closedir()` on
+/// `dir`. Null at the end of the directory.
 #[no_mangle]
-pub extern "C" fn readdir(dir: *const api::DIR) -> *mut api::dirent {
-	let fd = unsafe { &*dir }.fd;
-	let entry =
-		unsafe { alloc(Layout::new::<api::dirent>()) } as *mut api::dirent;
-	syscall::readdir(fd, entry);
+pub extern "C" fn readdir(dir: *mut api::DIR) -> *mut api::dirent {
+	let dir = unsafe { &mut *dir };
+	if dir.pos >= dir.end {
+		let len = getdents64(
+			dir.fd as c_int,
+			dir.buf.as_mut_ptr() as *mut c_void,
+			dir.buf.len(),
+		);
+		if len <= 0 {
+			return ptr::null_mut();
+		}
+		dir.pos = 0;
+		dir.end = len as usize;
+	}
+
+	let entry = dir.buf[dir.pos..].as_mut_ptr() as *mut api::dirent;
+	dir.pos += unsafe { (*entry).d_reclen } as usize;
 	entry
 }
+
+#[no_mangle]
+pub extern "C" fn closedir(dir: *mut api::DIR) -> c_int {
+	let dir = unsafe { Box::from_raw(dir) };
+	close(dir.fd as c_int)
+}
+
+/// Fill `dirp` with as many `dirent` records as fit in `count` bytes, returns
+/// the bytes used and 0 at the end of the directory.
+#[no_mangle]
+pub extern "C" fn getdents64(
+	fd: c_int,
+	dirp: *mut c_void,
+	count: usize,
+) -> isize {
+	let ret = syscall::syscall3(32, fd as u64, dirp as u64, count as u64);
+	errno::check(ret as isize)
+}
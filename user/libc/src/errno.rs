@@ -0,0 +1,14 @@
+use core::ffi::c_int;
+
+/// The error of the last failed call, successful calls leave it alone.
+#[no_mangle]
+pub static mut errno: c_int = 0;
+
+/// Turn a negative error number from the kernel into -1 and `errno`.
+pub(crate) fn check(ret: isize) -> isize {
+	if ret < 0 {
+		unsafe { errno = -ret as c_int };
+		return -1;
+	}
+	ret
+}
@@ -1,9 +1,24 @@
 use core::ffi::{c_char, c_int, CStr};
 
-use crate::syscall;
+use crate::{api::mode_t, errno, syscall};
 
+/// Declared variadic in C, `mode` is where the caller leaves the third
+/// argument and is only looked at with `O_CREAT`.
 #[no_mangle]
-pub extern "C" fn open(path: *const c_char, _oflag: c_int) -> c_int {
+pub extern "C" fn open(
+	path: *const c_char,
+	oflag: c_int,
+	mode: mode_t,
+) -> c_int {
 	let fname = unsafe { CStr::from_ptr(path).to_bytes() };
-	syscall::open(fname.as_ptr(), fname.len()) as c_int
+	errno::check(syscall::open(fname.as_ptr(), fname.len(), oflag, mode))
+		as c_int
+}
+
+/// Declared variadic in C, only `F_GETFD` and `F_SETFD` are supported and
+/// both take an int if anything.
+#[no_mangle]
+pub extern "C" fn fcntl(fildes: c_int, cmd: c_int, arg: c_int) -> c_int {
+	let ret = syscall::syscall3(26, fildes as u64, cmd as u64, arg as u64);
+	errno::check(ret as isize) as c_int
 }
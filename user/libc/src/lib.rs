@@ -4,13 +4,16 @@ extern crate alloc;
 
 pub mod api;
 pub mod dirent;
+pub mod errno;
 pub mod fcntl;
 pub mod malloc;
+pub mod mman;
 #[cfg(not(feature = "kernel"))]
 pub mod prelude;
 mod stat;
+pub mod stdio;
 pub mod sync;
 pub mod syscall;
 pub mod unistd;
 
-pub const PAGE_SIZE: usize = 0x200000;
+pub const PAGE_SIZE: usize = 0x1000;
@@ -1,6 +1,14 @@
 use core::alloc::{GlobalAlloc, Layout};
 
-use crate::{sync::SpinLock, syscall::brk, PAGE_SIZE};
+use crate::{
+	api,
+	sync::SpinLock,
+	syscall::{brk, mmap, munmap},
+	PAGE_SIZE,
+};
+
+/// Allocations this large get their own mapping instead of heap space.
+const MMAP_THRESHOLD: usize = 0x20000;
 
 pub struct Allocator {
 	placement: usize,
@@ -9,8 +17,8 @@ pub struct Allocator {
 
 impl Allocator {
 	pub fn init(&mut self) {
-		self.placement = brk(PAGE_SIZE);
-		self.max = self.placement + PAGE_SIZE;
+		self.placement = brk(0);
+		self.max = self.placement;
 	}
 
 	pub const fn new() -> Self {
@@ -23,6 +31,18 @@ impl Allocator {
 
 unsafe impl GlobalAlloc for SpinLock<Allocator> {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if layout.size() >= MMAP_THRESHOLD && layout.align() <= PAGE_SIZE {
+			let ptr = mmap(
+				0,
+				layout.size(),
+				api::PROT_READ | api::PROT_WRITE,
+				api::MAP_PRIVATE | api::MAP_ANONYMOUS,
+				-1,
+				0,
+			);
+			return if ptr == usize::MAX { 0 } else { ptr } as *mut u8;
+		}
+
 		let mut guard = self.lock();
 		let align = layout.align();
 		guard.placement = (guard.placement & !(align - 1)) + align;
@@ -30,7 +50,15 @@ unsafe impl GlobalAlloc for SpinLock<Allocator> {
 		let placement = guard.placement;
 		let next_placement = placement + layout.size();
 
-		let ptr = if next_placement < guard.max {
+		// Grow the heap a page at a time.
+		if next_placement > guard.max {
+			let max = brk(next_placement.next_multiple_of(PAGE_SIZE));
+			if max != usize::MAX {
+				guard.max = max;
+			}
+		}
+
+		let ptr = if next_placement <= guard.max {
 			guard.placement = next_placement;
 			placement
 		} else {
@@ -40,5 +68,9 @@ unsafe impl GlobalAlloc for SpinLock<Allocator> {
 		ptr as *mut u8
 	}
 
-	unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if layout.size() >= MMAP_THRESHOLD && layout.align() <= PAGE_SIZE {
+			munmap(ptr as usize, layout.size());
+		}
+	}
 }
@@ -0,0 +1,36 @@
+use core::ffi::{c_int, c_void};
+
+use crate::{api::off_t, errno, syscall};
+
+#[no_mangle]
+pub extern "C" fn mmap(
+	addr: *mut c_void,
+	len: usize,
+	prot: c_int,
+	flags: c_int,
+	fildes: c_int,
+	off: off_t,
+) -> *mut c_void {
+	syscall::mmap(
+		addr as usize,
+		len,
+		prot,
+		flags,
+		fildes as isize,
+		off as usize,
+	) as *mut c_void
+}
+
+#[no_mangle]
+pub extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
+	errno::check(syscall::munmap(addr as usize, len)) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn mprotect(
+	addr: *mut c_void,
+	len: usize,
+	prot: c_int,
+) -> c_int {
+	errno::check(syscall::mprotect(addr as usize, len, prot)) as c_int
+}
@@ -1,18 +1,41 @@
-use core::panic::PanicInfo;
+use core::{arch::global_asm, ffi::c_char, panic::PanicInfo};
 
-use crate::{malloc::Allocator, sync::SpinLock, syscall::exit};
+use crate::{
+	malloc::Allocator, sync::SpinLock, syscall::exit, unistd::environ,
+};
 
 extern "C" {
-	fn main() -> isize;
+	fn main(
+		argc: isize,
+		argv: *const *const c_char,
+		envp: *const *const c_char,
+	) -> isize;
 }
 
 #[global_allocator]
 static GLOBAL_ALLOC: SpinLock<Allocator> = SpinLock::new(Allocator::new());
 
-#[export_name = "_start"]
-unsafe fn _start() {
+// The kernel enters with argc, argv, envp and auxv on the stack, hand them to
+// Rust code with a terminated frame chain.
+global_asm!(
+	".global _start",
+	"_start:",
+	"xor rbp, rbp",
+	"mov rdi, rsp",
+	"and rsp, -16",
+	"call {start}",
+	"ud2",
+	start = sym start,
+);
+
+unsafe extern "C" fn start(sp: *const usize) -> ! {
+	let argc = *sp as isize;
+	let argv = sp.add(1) as *const *const c_char;
+	let envp = argv.add(argc as usize + 1);
+	environ = envp;
+
 	GLOBAL_ALLOC.lock().init();
-	exit(main())
+	exit(main(argc, argv, envp))
 }
 
 #[panic_handler]
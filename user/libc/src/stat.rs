@@ -1,8 +1,28 @@
-use core::ffi::c_int;
+use core::ffi::{c_char, c_int};
 
-use crate::{api, syscall};
+use crate::{api, errno, syscall};
+
+#[no_mangle]
+pub extern "C" fn stat(path: *const c_char, buf: *mut api::stat) -> c_int {
+	errno::check(syscall::syscall2(4, path as u64, buf as u64) as isize)
+		as c_int
+}
+
+/// The same as `stat()`, there are no symbolic links.
+#[no_mangle]
+pub extern "C" fn lstat(path: *const c_char, buf: *mut api::stat) -> c_int {
+	errno::check(syscall::syscall2(31, path as u64, buf as u64) as isize)
+		as c_int
+}
 
 #[no_mangle]
 pub extern "C" fn fstat(fildes: c_int, buf: *mut api::stat) -> c_int {
-	syscall::syscall2(10, fildes as u64, buf as u64) as c_int
+	let ret = syscall::syscall2(10, fildes as u64, buf as u64);
+	errno::check(ret as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn mkdir(path: *const c_char, mode: api::mode_t) -> c_int {
+	errno::check(syscall::syscall2(17, path as u64, mode as u64) as isize)
+		as c_int
 }
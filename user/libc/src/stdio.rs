@@ -0,0 +1,9 @@
+use core::ffi::{c_char, c_int};
+
+use crate::{errno, syscall};
+
+#[no_mangle]
+pub extern "C" fn rename(old: *const c_char, new: *const c_char) -> c_int {
+	let ret = syscall::syscall2(20, old as u64, new as u64);
+	errno::check(ret as isize) as c_int
+}
@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::api;
+use crate::errno;
 
 #[inline]
 pub(crate) fn syscall(number: u64) -> u64 {
@@ -68,6 +68,58 @@ pub(crate) fn syscall3(number: u64, a1: u64, a2: u64, a3: u64) -> u64 {
 	ret
 }
 
+#[inline]
+pub(crate) fn syscall4(number: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> u64 {
+	let mut ret;
+	unsafe {
+		asm!(
+			"syscall",
+			in("rax") number,
+			in("rdi") a1,
+			in("rsi") a2,
+			in("rdx") a3,
+			in("r10") a4,
+			out("rcx") _,
+			out("r11") _,
+			lateout("rax") ret
+		);
+	}
+	ret
+}
+
+#[inline]
+pub(crate) fn syscall6(
+	number: u64,
+	a1: u64,
+	a2: u64,
+	a3: u64,
+	a4: u64,
+	a5: u64,
+	a6: u64,
+) -> u64 {
+	let mut ret;
+	unsafe {
+		asm!(
+			"syscall",
+			in("rax") number,
+			in("rdi") a1,
+			in("rsi") a2,
+			in("rdx") a3,
+			in("r10") a4,
+			in("r8") a5,
+			in("r9") a6,
+			out("rcx") _,
+			out("r11") _,
+			lateout("rax") ret
+		);
+	}
+	ret
+}
+
+pub fn pipe(fildes: *mut i32) -> isize {
+	syscall1(27, fildes as u64) as isize
+}
+
 pub fn fork() -> isize {
 	syscall(9) as isize
 }
@@ -77,36 +129,57 @@ pub fn exit(status: isize) -> ! {
 	unreachable!()
 }
 
-pub fn open(path: *const u8, len: usize) -> isize {
-	syscall2(3, path as u64, len as u64) as isize
-}
-
-pub fn stat(fd: isize) {
-	syscall1(4, fd as u64);
+pub fn open(path: *const u8, len: usize, oflag: i32, mode: u16) -> isize {
+	syscall4(3, path as u64, len as u64, oflag as u64, mode as u64) as isize
 }
 
-pub fn write(_fd: u64, buf: *const u8, len: usize) {
-	syscall3(6, _fd, buf as u64, len as u64);
+pub fn write(_fd: u64, buf: *const u8, len: usize) -> isize {
+	syscall3(6, _fd, buf as u64, len as u64) as isize
 }
 
 pub fn chdir(buf: *const u8, len: usize) -> isize {
 	syscall2(8, buf as u64, len as u64) as isize
 }
 
-pub fn read(_fd: u64, buf: *mut u8, len: usize) -> usize {
-	syscall3(5, _fd, buf as u64, len as u64) as usize
-}
-
-pub fn readdir(fd: isize, buf: *mut api::dirent) -> isize {
-	syscall2(7, fd as u64, buf as u64) as isize
+pub fn read(_fd: u64, buf: *mut u8, len: usize) -> isize {
+	syscall3(5, _fd, buf as u64, len as u64) as isize
 }
 
 pub fn uptime() -> u64 {
 	syscall(401)
 }
 
-/// Currently brk() is only capable of returning the current break (addr = 0) or
-/// incrementing the break by one page (addr != 0).
+/// Move the break to `addr` and return it, or return the current break if
+/// `addr` is 0. Returns `usize::MAX` if the break can't be moved.
 pub fn brk(addr: usize) -> usize {
-	syscall1(2, addr as u64) as usize
+	errno::check(syscall1(2, addr as u64) as isize) as usize
+}
+
+/// Returns `usize::MAX` on failure.
+pub fn mmap(
+	addr: usize,
+	len: usize,
+	prot: i32,
+	flags: i32,
+	fd: isize,
+	offset: usize,
+) -> usize {
+	let ret = syscall6(
+		14,
+		addr as u64,
+		len as u64,
+		prot as u64,
+		flags as u64,
+		fd as u64,
+		offset as u64,
+	);
+	errno::check(ret as isize) as usize
+}
+
+pub fn munmap(addr: usize, len: usize) -> isize {
+	syscall2(15, addr as u64, len as u64) as isize
+}
+
+pub fn mprotect(addr: usize, len: usize, prot: i32) -> isize {
+	syscall3(16, addr as u64, len as u64, prot as u64) as isize
 }
@@ -1,38 +1,164 @@
-use core::ffi::{c_char, c_int, c_void, CStr};
+use core::{
+	ffi::{c_char, c_int, c_void, CStr},
+	ptr,
+};
 
-use crate::syscall;
+use crate::{api, errno, syscall};
+
+/// The environment of this process, set up by `_start`.
+#[no_mangle]
+pub static mut environ: *const *const c_char = ptr::null();
 
 #[no_mangle]
 pub fn chdir(path: *const c_char) -> c_int {
 	let path = unsafe { CStr::from_ptr(path) }
 		.to_str()
 		.expect("utf8 error");
-	syscall::chdir(path.as_ptr(), path.len()) as c_int
+	errno::check(syscall::chdir(path.as_ptr(), path.len())) as c_int
 }
 
 #[no_mangle]
 pub fn getcwd(buf: *mut c_char, size: usize) -> *mut c_char {
-	syscall::syscall2(11, buf as u64, size as u64);
+	let ret = syscall::syscall2(11, buf as u64, size as u64) as isize;
+	if errno::check(ret) < 0 {
+		return ptr::null_mut();
+	}
 	buf
 }
 
 #[no_mangle]
 pub extern "C" fn read(fildes: c_int, buf: *mut c_void, nbyte: usize) -> isize {
-	syscall::read(fildes as u64, buf as *mut u8, nbyte) as isize
+	errno::check(syscall::read(fildes as u64, buf as *mut u8, nbyte))
 }
 
 #[no_mangle]
 pub extern "C" fn write(fd: c_int, buf: *const c_void, len: usize) -> isize {
-	syscall::write(fd as u64, buf as *const u8, len);
-	len as isize
+	errno::check(syscall::write(fd as u64, buf as *const u8, len))
+}
+
+#[no_mangle]
+pub extern "C" fn pread(
+	fildes: c_int,
+	buf: *mut c_void,
+	nbyte: usize,
+	offset: api::off_t,
+) -> isize {
+	errno::check(syscall::syscall4(
+		29,
+		fildes as u64,
+		buf as u64,
+		nbyte as u64,
+		offset as u64,
+	) as isize)
+}
+
+#[no_mangle]
+pub extern "C" fn pwrite(
+	fildes: c_int,
+	buf: *const c_void,
+	nbyte: usize,
+	offset: api::off_t,
+) -> isize {
+	errno::check(syscall::syscall4(
+		30,
+		fildes as u64,
+		buf as u64,
+		nbyte as u64,
+		offset as u64,
+	) as isize)
+}
+
+/// Returns the new offset from the start of the file.
+#[no_mangle]
+pub extern "C" fn lseek(
+	fildes: c_int,
+	offset: api::off_t,
+	whence: c_int,
+) -> api::off_t {
+	let ret =
+		syscall::syscall3(28, fildes as u64, offset as u64, whence as u64);
+	errno::check(ret as isize) as api::off_t
+}
+
+/// Open a pipe, its read end goes in `fildes[0]` and its write end in
+/// `fildes[1]`.
+#[no_mangle]
+pub extern "C" fn pipe(fildes: *mut c_int) -> c_int {
+	errno::check(syscall::pipe(fildes)) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn close(fildes: c_int) -> c_int {
+	errno::check(syscall::syscall1(23, fildes as u64) as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn dup(fildes: c_int) -> c_int {
+	errno::check(syscall::syscall1(24, fildes as u64) as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn dup2(fildes: c_int, fildes2: c_int) -> c_int {
+	let ret = syscall::syscall2(25, fildes as u64, fildes2 as u64);
+	errno::check(ret as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn execve(
+	path: *const c_char,
+	argv: *const *const c_char,
+	envp: *const *const c_char,
+) -> c_int {
+	let ret = syscall::syscall3(12, path as u64, argv as u64, envp as u64);
+	errno::check(ret as isize) as c_int
 }
 
+/// Run `pathname` with just its name as argument and the current environment.
 #[no_mangle]
 pub extern "C" fn exec(pathname: *const c_char) -> c_int {
-	syscall::syscall1(12, pathname as u64) as c_int
+	let argv = [pathname, ptr::null()];
+	execve(pathname, argv.as_ptr(), unsafe { environ })
+}
+
+#[no_mangle]
+pub extern "C" fn rmdir(path: *const c_char) -> c_int {
+	errno::check(syscall::syscall1(18, path as u64) as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn unlink(path: *const c_char) -> c_int {
+	errno::check(syscall::syscall1(19, path as u64) as isize) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn link(path1: *const c_char, path2: *const c_char) -> c_int {
+	let ret = syscall::syscall2(21, path1 as u64, path2 as u64);
+	errno::check(ret as isize) as c_int
+}
+
+/// Write everything the kernel has cached back to the disks.
+#[no_mangle]
+pub extern "C" fn sync() {
+	syscall::syscall(22);
 }
 
 #[no_mangle]
 pub extern "C" fn fork() -> isize {
-	syscall::syscall(9) as isize
+	errno::check(syscall::fork())
+}
+
+#[no_mangle]
+pub extern "C" fn waitpid(
+	pid: api::pid_t,
+	stat_loc: *mut c_int,
+	options: c_int,
+) -> api::pid_t {
+	let ret =
+		syscall::syscall3(13, pid as u64, stat_loc as u64, options as u64);
+	errno::check(ret as isize) as api::pid_t
+}
+
+#[no_mangle]
+pub extern "C" fn wait(stat_loc: *mut c_int) -> api::pid_t {
+	waitpid(-1, stat_loc, 0)
 }
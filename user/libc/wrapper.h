@@ -1,6 +1,11 @@
 #include "dirent.h"
+#include "errno.h"
 #include "stdint.h"
 #include "sys/types.h"
 #include "unistd.h"
 #include "fcntl.h"
+#include "signal.h"
 #include "sys/stat.h"
+#include "sys/wait.h"
+#include "sys/mman.h"
+#include "stdio.h"
@@ -3,21 +3,31 @@
 
 extern crate alloc;
 
-use alloc::{ffi::CString, format, vec};
+use alloc::{ffi::CString, format, string::String, vec, vec::Vec};
 use core::{
-	ffi::{c_char, c_void, CStr},
-	slice, str,
+	ffi::{c_char, c_int, c_void, CStr},
+	mem, ptr, slice, str,
 };
 
 use libc::{
-	api::{getcwd, STDIN_FILENO, STDOUT_FILENO},
-	dirent::{opendir, readdir},
+	api::{
+		getcwd, O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY, STDIN_FILENO,
+		STDOUT_FILENO, WNOHANG,
+	},
+	dirent::{closedir, opendir, readdir},
 	fcntl::open,
 	syscall,
-	syscall::fork,
-	unistd::{chdir, read, write},
+	unistd::{chdir, close, dup2, environ, execve, pipe, read, waitpid, write},
 };
 
+/// One program of a pipeline, with the files it reads from and writes to
+/// instead of the pipe or terminal.
+struct Command<'a> {
+	args: Vec<&'a str>,
+	input: Option<&'a str>,
+	output: Option<&'a str>,
+}
+
 fn shell() {
 	let mut cwd_buf = [0 as c_char; 128];
 	unsafe { getcwd(cwd_buf.as_mut_ptr(), cwd_buf.len()) };
@@ -30,6 +40,7 @@ fn shell() {
 
 	let mut line_buf = [0u8; 128];
 	loop {
+		reap_jobs();
 		write(
 			STDOUT_FILENO,
 			prompt.as_ptr() as *const c_void,
@@ -63,7 +74,17 @@ fn shell() {
 				);
 			}
 			Some("cat") => cat(tokens.next()),
-			Some("run") => run(tokens.next()),
+			Some("run") => {
+				let mut args: Vec<&str> = tokens.collect();
+				let background = args.last() == Some(&"&");
+				if background {
+					args.pop();
+				}
+				match parse_pipeline(&args) {
+					Some(pipeline) => run(&pipeline, background),
+					None => print("syntax error\n"),
+				}
+			}
 			_ => continue,
 		}
 	}
@@ -79,14 +100,11 @@ fn print(message: &str) {
 
 fn cat(path: Option<&str>) {
 	let Some(path) = path else { return };
-	let file = open(CString::new(path).unwrap().as_ptr(), 0);
+	let file = open(CString::new(path).unwrap().as_ptr(), O_RDONLY as c_int, 0);
 	if file < 0 {
 		return;
 	}
-	let mut stat = libc::api::stat {
-		st_mode: 0,
-		st_size: 0,
-	};
+	let mut stat: libc::api::stat = unsafe { mem::zeroed() };
 	let ret = unsafe { libc::api::fstat(file, &mut stat) };
 	if ret < 0 {
 		return;
@@ -122,13 +140,17 @@ fn ls(path: Option<&str>) {
 	let path = path
 		.map(|rstr| CString::new(rstr).unwrap())
 		.unwrap_or(CString::new(".").unwrap());
-	let fd_root = opendir(path.as_ptr());
+	let dir = opendir(path.as_ptr());
+	if dir.is_null() {
+		return;
+	}
 
 	loop {
-		let entry = unsafe { &*readdir(fd_root) };
-		if entry.d_ino == 0 {
+		let entry = readdir(dir);
+		if entry.is_null() {
 			break;
 		}
+		let entry = unsafe { &*entry };
 		let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }
 			.to_str()
 			.unwrap();
@@ -136,14 +158,154 @@ fn ls(path: Option<&str>) {
 
 		write(STDOUT_FILENO, name.as_ptr() as *const c_void, name.len());
 	}
+	closedir(dir);
+}
+
+/// Split `a < in | b | c > out` into its commands, None if a command or a
+/// file name is missing.
+fn parse_pipeline<'a>(tokens: &[&'a str]) -> Option<Vec<Command<'a>>> {
+	let mut pipeline = Vec::new();
+	for tokens in tokens.split(|&token| token == "|") {
+		let mut command = Command {
+			args: Vec::new(),
+			input: None,
+			output: None,
+		};
+
+		let mut tokens = tokens.iter();
+		while let Some(&token) = tokens.next() {
+			match token {
+				"<" => command.input = Some(*tokens.next()?),
+				">" => command.output = Some(*tokens.next()?),
+				arg => command.args.push(arg),
+			}
+		}
+
+		if command.args.is_empty() {
+			return None;
+		}
+		pipeline.push(command);
+	}
+	Some(pipeline)
 }
 
-fn run(path: Option<&str>) {
-	if let Some(path) = path {
-		let exec_path = CString::new(path).unwrap();
-		if libc::unistd::fork() == 0 {
-			libc::unistd::exec(exec_path.as_ptr());
+/// Run every command of `pipeline` with each one's output piped into the
+/// next one's input.
+fn run(pipeline: &[Command], background: bool) {
+	let mut pids = Vec::with_capacity(pipeline.len());
+	// Read end of the pipe from the previous command.
+	let mut input = None;
+
+	for (i, command) in pipeline.iter().enumerate() {
+		let last = i == pipeline.len() - 1;
+		let mut fildes = [0 as c_int; 2];
+		if !last && pipe(fildes.as_mut_ptr()) < 0 {
+			print("can't create a pipe\n");
+			if let Some(input) = input.take() {
+				close(input);
+			}
+			break;
+		}
+
+		let pid = libc::unistd::fork();
+		if pid < 0 {
+			print("can't fork\n");
+			if let Some(input) = input.take() {
+				close(input);
+			}
+			if !last {
+				close(fildes[0]);
+				close(fildes[1]);
+			}
+			break;
+		}
+		if pid == 0 {
+			if let Some(input) = input {
+				dup2(input, STDIN_FILENO as c_int);
+				close(input);
+			}
+			if !last {
+				dup2(fildes[1], STDOUT_FILENO as c_int);
+				close(fildes[0]);
+				close(fildes[1]);
+			}
+			exec_command(command);
 		}
+
+		// The children hold their own copies of the pipe ends.
+		if let Some(input) = input.take() {
+			close(input);
+		}
+		if !last {
+			close(fildes[1]);
+			input = Some(fildes[0]);
+		}
+		pids.push(pid);
+	}
+
+	for pid in pids {
+		if background {
+			print(&format!("[{pid}]\n"));
+			continue;
+		}
+
+		let mut status = 0;
+		if waitpid(pid as i64, &mut status, 0) == pid as i64 {
+			print(&format!("[{pid}] {}\n", describe_status(status)));
+		}
+	}
+}
+
+/// Apply the redirections of `command` and run it in place of this process.
+fn exec_command(command: &Command) -> ! {
+	let redirect = |path: &str, oflag: c_int, fildes: c_int| {
+		let path = CString::new(path).unwrap();
+		let file = open(path.as_ptr(), oflag, 0o644);
+		if file < 0 {
+			print(&format!("can't open {}\n", path.to_str().unwrap()));
+			syscall::exit(1);
+		}
+		dup2(file, fildes);
+		close(file);
+	};
+	if let Some(path) = command.input {
+		redirect(path, O_RDONLY as c_int, STDIN_FILENO as c_int);
+	}
+	if let Some(path) = command.output {
+		let oflag = O_WRONLY | O_CREAT | O_TRUNC;
+		redirect(path, oflag as c_int, STDOUT_FILENO as c_int);
+	}
+
+	let args: Vec<CString> = command
+		.args
+		.iter()
+		.map(|arg| CString::new(*arg).unwrap())
+		.collect();
+	let mut argv: Vec<*const c_char> =
+		args.iter().map(|arg| arg.as_ptr()).collect();
+	argv.push(ptr::null());
+
+	execve(argv[0], argv.as_ptr(), unsafe { environ });
+	// Only returns if exec failed.
+	syscall::exit(127)
+}
+
+/// Report background jobs that have finished since the last prompt.
+fn reap_jobs() {
+	let mut status = 0;
+	loop {
+		let pid = waitpid(-1, &mut status, WNOHANG);
+		if pid <= 0 {
+			break;
+		}
+		print(&format!("[{pid}] done, {}\n", describe_status(status)));
+	}
+}
+
+fn describe_status(status: i32) -> String {
+	match status & 0x7F {
+		0 => format!("exited with status {}", (status >> 8) & 0xFF),
+		signal => format!("killed by signal {signal}"),
 	}
 }
 